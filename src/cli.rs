@@ -0,0 +1,464 @@
+//! The command line interface behind `read_input`.
+use super::*;
+use std::path::Path;
+
+const USAGE: &str = "Usage:
+  single-variable-algebra-compiler <COMMAND> [OPTIONS] [FILE]
+
+Commands:
+  run [FILE]             Evaluate all queries of a program (FILE defaults to stdin)
+  check [FILE]           Report errors without evaluating anything
+  fmt [FILE]             Print the program formatted, --check fails if it is not
+  expand [FILE] [NAME]   Inline all function calls of NAME (defaults to the last definition)
+  flatten [FILE]         Print the program with its imports and use prelude as one pure SVA file
+  derive [FILE] [NAME]   Print the derivative of NAME, --check compares it with finite differences
+                         at the inputs of the queries of NAME to --digits places (default 10)
+  normalize [FILE] [NAME]
+                         Print NAME expanded as a polynomial or rational function with exact
+                         coefficients, if it is one
+  equiv [FILE] F G       Check that F and G compute the same function, with their normal forms or
+                         at sample inputs to --digits places (default 10)
+  grade EXERCISES [FILE] Run the functions of FILE on the examples of the exercises in the JSON
+                         file EXERCISES and print the score, fails unless all examples pass.
+                         EXERCISES is a list of TestCases, an exercise pack or `curriculum`
+  tm2sva TM              Translate a Turing machine in standard text format to SVA
+  repl                   Start the interactive mode
+  help                   Print this message
+
+Options:
+  --decimals N           Decimal places of the math tricks, like `decimals(x) = N` in the first line
+  --no-tricks            Disable the math tricks (Accelerated Simulator)
+  --digits N             Decimal places derive --check and equiv compare, default 10
+  --output FORMAT        text, csv or json
+  --time                 Print the elapsed time to stderr
+  --tolerance X          grade accepts numbers that differ from the expected ones by at most X
+  --width N              fmt wraps lines longer than N chars
+  --strict               run and check fail on implicit multiplication like 2x
+  --unicode              expand prints ·, ÷, −, superscripts and √
+
+Without a command, the arguments are the lines of the program:
+  single-variable-algebra-compiler 'F(x)=4+4' 'G(x)=F(x)*2' 'G(1)'
+  echo 'F(x)=4+4
+G(x)=F(x)*2
+G(0..1 step 0.5)' | single-variable-algebra-compiler";
+
+struct Options {
+    decimals: Option<usize>,
+    use_math_tricks: bool,
+    digits: Option<usize>,
+    format: OutputFormat,
+    time: bool,
+    check: bool,
+    width: Option<usize>,
+    tolerance: Option<Dec>,
+    strict: bool,
+    unicode: bool,
+}
+
+pub(crate) fn main(args: Vec<String>) {
+    let (command, args) = match args.first().map(|s| s.as_str()) {
+        Some(
+            c @ ("run" | "check" | "fmt" | "expand" | "flatten" | "derive" | "normalize" | "equiv"
+            | "grade" | "tm2sva" | "repl" | "help"),
+        ) => (c, &args[1..]),
+        _ => ("", &args[..]),
+    };
+    let (options, operands) = match parse_options(args) {
+        Ok(parsed) => parsed,
+        Err(e) => return fail(e),
+    };
+    if command == "repl" {
+        return repl::main();
+    }
+    // Instant::now() is not available in the browser.
+    let start = options.time.then(std::time::Instant::now);
+    let result = match command {
+        "" => read_program(&operands, true).and_then(|src| run(&src, &options)),
+        "run" => read_program(&operands, false).and_then(|src| run(&src, &options)),
+        "check" => read_program(&operands, false).and_then(|src| check(&src, &options)),
+        "fmt" => read_source(&operands, false).and_then(|src| fmt(&src, &options)),
+        "expand" => read_program(&operands[..operands.len().min(1)], false)
+            .and_then(|src| expand(&src, operands.get(1), &options)),
+        "derive" => read_program(&operands[..operands.len().min(1)], false)
+            .and_then(|src| derive(&src, operands.get(1), &options)),
+        "normalize" => read_program(&operands[..operands.len().min(1)], false)
+            .and_then(|src| normalize(&src, operands.get(1), &options)),
+        "equiv" => {
+            read_program(&operands[..operands.len().saturating_sub(2)], false).and_then(|src| {
+                equiv(
+                    &src,
+                    &operands[operands.len().saturating_sub(2)..],
+                    &options,
+                )
+            })
+        }
+        "grade" => match operands.split_first() {
+            Some((exercises, program)) => {
+                read_program(program, false).and_then(|src| grade(exercises, &src, &options))
+            }
+            None => Err("Missing exercises".to_string()),
+        },
+        "flatten" => read_source(&operands, false)
+            .and_then(|src| import::flatten(&src, source_file(&operands)))
+            .map(|flat| flat.trim_end().to_string()),
+        "tm2sva" => match operands.first() {
+            Some(tm) => turing_machine::to_sva(tm, options.decimals.unwrap_or(50)),
+            None => Err("Missing Turing machine".to_string()),
+        },
+        _ => Ok(USAGE.to_string()),
+    };
+    match result {
+        Ok(s) => output(s),
+        Err(e) => fail(e),
+    }
+    if let Some(start) = start {
+        eprintln!("Finished in {:.3?}", start.elapsed());
+    }
+}
+
+fn fail(message: String) {
+    output(message);
+    #[cfg(not(target_arch = "wasm32"))]
+    std::process::exit(1);
+}
+
+fn parse_options(args: &[String]) -> Result<(Options, Vec<String>), String> {
+    let mut options = Options {
+        decimals: None,
+        use_math_tricks: true,
+        digits: None,
+        format: OutputFormat::Text,
+        time: false,
+        check: false,
+        width: None,
+        tolerance: None,
+        strict: false,
+        unicode: false,
+    };
+    let mut operands = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--decimals" => {
+                let k = args.next().ok_or("Missing value of --decimals")?;
+                options.decimals = Some(
+                    k.parse()
+                        .map_err(|_| format!("Invalid value of --decimals: {k}"))?,
+                );
+            }
+            "--no-tricks" => options.use_math_tricks = false,
+            "--digits" => {
+                let n = args.next().ok_or("Missing value of --digits")?;
+                options.digits = Some(
+                    n.parse()
+                        .map_err(|_| format!("Invalid value of --digits: {n}"))?,
+                );
+            }
+            "--output" => {
+                options.format = match args.next().map(|s| s.as_str()) {
+                    Some("text") => OutputFormat::Text,
+                    Some("csv") => OutputFormat::Csv,
+                    Some("json") => OutputFormat::Json,
+                    other => return Err(format!("Unknown output format: {}", other.unwrap_or(""))),
+                }
+            }
+            "--time" => options.time = true,
+            "--check" => options.check = true,
+            "--strict" => options.strict = true,
+            "--unicode" => options.unicode = true,
+            "--width" => {
+                let n = args.next().ok_or("Missing value of --width")?;
+                options.width = Some(
+                    n.parse()
+                        .map_err(|_| format!("Invalid value of --width: {n}"))?,
+                );
+            }
+            "--tolerance" => {
+                let x = args.next().ok_or("Missing value of --tolerance")?;
+                options.tolerance = Some(
+                    x.parse()
+                        .map_err(|_| format!("Invalid value of --tolerance: {x}"))?,
+                );
+            }
+            _ => operands.push(arg.clone()),
+        }
+    }
+    Ok((options, operands))
+}
+
+/// Reads a file, stdin (no operand or `-`) or, if `inline`, the operands as lines of the program.
+fn read_source(operands: &[String], inline: bool) -> Result<String, String> {
+    let src = match operands {
+        [] => String::new(),
+        _ if inline => operands.join("\n"),
+        [path] if path != "-" => {
+            std::fs::read_to_string(path).map_err(|e| format!("Cannot read {path}: {e}"))?
+        }
+        [_] => String::new(),
+        _ => return Err(format!("Expected one file, got {}", operands.len())),
+    };
+    if !src.is_empty() {
+        return Ok(src);
+    }
+    let mut buffer = String::new();
+    io::stdin()
+        .read_to_string(&mut buffer)
+        .map_err(|e| format!("Cannot read stdin: {e}"))?;
+    if split_source(&buffer).is_empty() {
+        return Err(USAGE.to_string());
+    }
+    Ok(buffer)
+}
+
+/// The file read by read_source, imports are relative to it.
+fn source_file(operands: &[String]) -> Option<&Path> {
+    match operands {
+        [path] if path != "-" => Some(Path::new(path)),
+        _ => None,
+    }
+}
+
+/// read_source with the imports resolved.
+fn read_program(operands: &[String], inline: bool) -> Result<String, String> {
+    let src = read_source(operands, inline)?;
+    let file = if inline { None } else { source_file(operands) };
+    import::resolve_imports(&src, file)
+}
+
+fn parse(src: &str) -> Result<Program, String> {
+    parse_program(src).map_err(|errors| {
+        errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    })
+}
+
+/// `decimals(x) = k` or `decimals = k` in the first line enables the math tricks and sets the decimal places to k.
+/// `--decimals` does the same and wins over the first line.
+fn use_math_tricks(src: &str, options: &Options) -> bool {
+    // The prelude functions are always eligible for the math tricks, so with `use prelude` the
+    // decimals line may be anywhere.
+    let lines = split_source(src);
+    let searched = if prelude::is_used(src) {
+        lines.len()
+    } else {
+        1
+    };
+    let decimals_line = lines.iter().take(searched).find_map(|l| {
+        let l = l.text.trim();
+        l.strip_prefix("decimals(x) =")
+            .or_else(|| l.strip_prefix("decimals ="))
+    });
+    let decimals = options.decimals.or_else(|| {
+        decimals_line
+            .and_then(|k| k.split_whitespace().next())
+            .and_then(|k| k.parse().ok())
+    });
+    if let Some(k) = decimals {
+        set_decimal_places(k);
+    }
+    options.use_math_tricks && (options.decimals.is_some() || decimals_line.is_some())
+}
+
+fn run(src: &str, options: &Options) -> Result<String, String> {
+    let use_math_tricks = use_math_tricks(src, options);
+    let program = parse(src)?;
+    strict(src, options)?;
+    let results = program.run(use_math_tricks)?;
+    Ok(format_results(&results, options.format))
+}
+
+/// Migration warnings are printed, but do not fail the check.
+fn check(src: &str, options: &Options) -> Result<String, String> {
+    let program = parse(src)?;
+    strict(src, options)?;
+    let mut lines: Vec<String> = migration_warnings(src)
+        .iter()
+        .map(|w| format!("warning: {w}"))
+        .collect();
+    let errors = program.check();
+    if !errors.is_empty() {
+        lines.extend(errors);
+        return Err(lines.join("\n"));
+    }
+    let constants = program.constants();
+    if !constants.is_empty() {
+        lines.push(format!("Constants: {}", constants.join(", ")));
+    }
+    lines.push(format!(
+        "OK: {} definitions, {} queries",
+        program.definitions.len(),
+        program.queries.len()
+    ));
+    Ok(lines.join("\n"))
+}
+
+/// With `--strict`, implicit multiplication is an error.
+fn strict(src: &str, options: &Options) -> Result<(), String> {
+    let errors = implicit_multiplications(src);
+    if !options.strict || errors.is_empty() {
+        return Ok(());
+    }
+    Err(errors
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+fn fmt(src: &str, options: &Options) -> Result<String, String> {
+    let format_options = formatter::FormatOptions {
+        max_width: options.width,
+    };
+    let formatted = formatter::format_source(src, format_options).map_err(|errors| {
+        errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    })?;
+    if !options.check {
+        return Ok(formatted.trim_end().to_string());
+    }
+    match src.lines().zip(formatted.lines()).position(|(a, b)| a != b) {
+        None if src == formatted => Ok("OK: formatted".to_string()),
+        Some(i) => Err(format!("Not formatted, first difference in line {}", i + 1)),
+        None => Err(format!(
+            "Not formatted, first difference in line {}",
+            src.lines().count().min(formatted.lines().count()) + 1
+        )),
+    }
+}
+
+/// NAME or the last definition.
+fn definition_name(program: &Program, name: Option<&String>) -> Result<String, String> {
+    match name {
+        Some(name) => Ok(name.clone()),
+        None => Ok(program
+            .definitions
+            .last()
+            .ok_or("The program has no definitions")?
+            .name
+            .clone()),
+    }
+}
+
+fn expand(src: &str, name: Option<&String>, options: &Options) -> Result<String, String> {
+    let program = parse(src)?;
+    let name = definition_name(&program, name)?;
+    let expanded = program.expand(&name)?;
+    let style = ExpressionStyle {
+        unicode: options.unicode,
+        ..Default::default()
+    };
+    Ok(format!(
+        "{name}(x) = {}",
+        create_expression_styled(expanded, style)
+    ))
+}
+
+fn normalize(src: &str, name: Option<&String>, options: &Options) -> Result<String, String> {
+    let program = parse(src)?;
+    let name = definition_name(&program, name)?;
+    let normal_form = program
+        .normal_form(&name)?
+        .ok_or(format!("{name} is no polynomial or rational function of x"))?;
+    let style = ExpressionStyle {
+        unicode: options.unicode,
+        ..Default::default()
+    };
+    Ok(format!(
+        "{name}(x) = {}",
+        create_expression_styled(normal_form.to_tree(), style)
+    ))
+}
+
+fn equiv(src: &str, names: &[String], options: &Options) -> Result<String, String> {
+    let [f, g] = names else {
+        return Err("equiv needs two function names".to_string());
+    };
+    let use_math_tricks = use_math_tricks(src, options);
+    let program = parse(src)?;
+    let digits = options.digits.unwrap_or(10);
+    match program.equivalent(f, g, digits, use_math_tricks)? {
+        equivalence::Equivalence::SameNormalForm(normal_form) => Ok(format!(
+            "OK: {f} and {g} have the normal form {normal_form}"
+        )),
+        equivalence::Equivalence::SameSamples(n) => Ok(format!(
+            "OK: {f} and {g} agree at {n} sample inputs to {digits} places"
+        )),
+        equivalence::Equivalence::Counterexample { x, f: a, g: b } => {
+            let x = trim2(x);
+            Err(format!("{f}({x}) = {a} but {g}({x}) = {b}"))
+        }
+    }
+}
+
+fn grade(exercises: &str, src: &str, options: &Options) -> Result<String, String> {
+    let json = match exercises {
+        "curriculum" => exercise_pack::CURRICULUM.to_string(),
+        path => std::fs::read_to_string(path).map_err(|e| format!("Cannot read {path}: {e}"))?,
+    };
+    // Sets the decimal places the pack is loaded with. The grader never uses the math tricks.
+    use_math_tricks(src, options);
+    let exercises = if json.trim_start().starts_with('[') {
+        grader::load_exercises(&json)?
+    } else {
+        exercise_pack::load(&json, get_decimal_places())?
+    };
+    let grade_options = grader::GradeOptions {
+        tolerance: options.tolerance.clone().unwrap_or_default(),
+    };
+    let report = grader::grade(&exercises, src, &grade_options)?;
+    let text = match options.format {
+        OutputFormat::Json => report.to_json(),
+        _ => report.to_string(),
+    };
+    if report.passed < report.total {
+        return Err(text);
+    }
+    Ok(text)
+}
+
+fn derive(src: &str, name: Option<&String>, options: &Options) -> Result<String, String> {
+    let program = parse(src)?;
+    let name = definition_name(&program, name)?;
+    let derivative = program.derivative(&name)?;
+    let style = ExpressionStyle {
+        unicode: options.unicode,
+        ..Default::default()
+    };
+    let mut lines = vec![format!(
+        "{name}'(x) = {}",
+        create_expression_styled(derivative, style)
+    )];
+    if !options.check {
+        return Ok(lines.join("\n"));
+    }
+    let digits = options.digits.unwrap_or(10);
+    let mut inputs = Vec::new();
+    for query in program.queries.iter().filter(|q| q.name() == name) {
+        inputs.extend(program.query_inputs(query, false)?);
+    }
+    if inputs.is_empty() {
+        return Err(format!("--check needs queries of {name}"));
+    }
+    let mut errors = Vec::new();
+    for (_, input) in &inputs {
+        let x = program.evaluate_constant(input, false)?;
+        if let Err(e) = program.verify_derivative(&name, &x, digits) {
+            errors.push(e);
+        }
+    }
+    if !errors.is_empty() {
+        lines.extend(errors);
+        return Err(lines.join("\n"));
+    }
+    lines.push(format!(
+        "OK: matches the finite differences at {} inputs to {digits} places",
+        inputs.len()
+    ));
+    Ok(lines.join("\n"))
+}
@@ -14,20 +14,31 @@ pub fn dec_to_string(x: Dec) -> String {
     x.to_plain_string()
 }
 
-pub fn pow(x: Dec, exp: Dec) -> Dec {
-    let x_str = x.to_string();
-    let exp_str = exp.to_string();
+/// None if the exponent or the result is too large, like for `2^1e20`.
+pub fn pow(x: Dec, exp: Dec) -> Option<Dec> {
+    let x_str = dec_to_string(x.clone());
+    let exp_str = dec_to_string(exp.clone());
     if x_str == "0" || x_str == "1" {
-        x
+        Some(x)
     } else if exp_str == "0.5" {
-        x.sqrt().unwrap()
+        x.sqrt()
     } else if exp_str.contains('.') {
         let result: f64 = x_str
             .parse::<f64>()
-            .unwrap()
-            .powf(exp_str.parse::<f64>().unwrap());
-        bigdecimal::FromPrimitive::from_f64(result).unwrap()
+            .ok()?
+            .powf(exp_str.parse::<f64>().ok()?);
+        bigdecimal::FromPrimitive::from_f64(result)
     } else {
-        x.powi(exp_str.parse::<i64>().unwrap())
+        Some(x.powi(exp_str.parse::<i64>().ok()?))
     }
 }
+
+/// `x` rounded to `decimals` decimal places.
+pub fn round(x: Dec, decimals: usize) -> Dec {
+    x.round(decimals as i64)
+}
+
+/// The greatest integer that is not greater than `x`.
+pub fn floor(x: Dec) -> Dec {
+    x.with_scale_round(0, bigdecimal::RoundingMode::Floor)
+}
@@ -0,0 +1,247 @@
+//! Symbolic differentiation behind `derive`.
+use super::*;
+
+impl Program {
+    /// The derivative d/dx of the definition `name`, simplified. Calls are differentiated with
+    /// the chain rule: `f(u)` becomes `f'(u)*u'` with the derivative of f inlined, `f^[n](u)`
+    /// the product of n such factors. A power with x in the exponent needs a definition of
+    /// `ln(x)`, SVA has no logarithm.
+    pub fn derivative(&self, name: &str) -> Result<TreeNode, String> {
+        let errors = self.check();
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+        let tree = self
+            .definitions
+            .iter()
+            .find(|t| t.name == name)
+            .ok_or(format!("Function {name} not defined"))?;
+        let mut deriver = Deriver {
+            program: self,
+            constants: self.constants(),
+            derived: Vec::new(),
+        };
+        let result = deriver.derive(&tree.root_node)?;
+        if count_nodes(&result) > MAX_EXPANDED_NODES {
+            return Err(format!(
+                "The derivative of {name} has more than {MAX_EXPANDED_NODES} nodes"
+            ));
+        }
+        Ok(result)
+    }
+
+    /// Compares the derivative of `name` at `x` with the central difference
+    /// `(f(x+h) - f(x-h))/(2h)` for `h = 10^-digits`. They must agree to `digits` decimal places,
+    /// relative to the size of the derivative. Powers like `x^(1/3)` are computed with f64
+    /// precision, so more than 6 digits are only meaningful without them.
+    pub fn verify_derivative(&self, name: &str, x: &Dec, digits: usize) -> Result<(), String> {
+        let derivative = self.derivative(name)?;
+        let h: Dec = format!("1e-{digits}").parse().unwrap();
+        let value = |x: Dec| apply_function(name, 1, Value::Number(x), &self.definitions, false);
+        let symbolic = apply_algebra_to_tree_node(&derivative, x, &self.definitions, false);
+        let numeric = value(x + &h).combine(value(x - &h), |right, left| {
+            Value::Number((right - left) / (&h * Dec::from(2)))
+        });
+        let at = format!("{name}'({})", trim2(x.clone()));
+        let (Value::Number(symbolic), Value::Number(numeric)) = (&symbolic, &numeric) else {
+            return Err(format!(
+                "{at} is {symbolic} and the finite difference is {numeric}"
+            ));
+        };
+        let tolerance: Dec = &h * (Dec::from(1) + symbolic.abs());
+        if (symbolic - numeric).abs() > tolerance {
+            return Err(format!(
+                "{at} is {} but the finite difference is {}",
+                trim2(symbolic.clone()),
+                trim2(numeric.round(digits as i64 + 2))
+            ));
+        }
+        Ok(())
+    }
+}
+
+struct Deriver<'a> {
+    program: &'a Program,
+    constants: Vec<String>,
+    /// The derivatives of the called functions, in x.
+    derived: Vec<(String, TreeNode)>,
+}
+
+impl Deriver<'_> {
+    fn depends_on_x(&self, node: &TreeNode) -> bool {
+        self.program.depends_on_x(node, &self.constants)
+    }
+
+    fn derive(&mut self, node: &TreeNode) -> Result<TreeNode, String> {
+        if !self.depends_on_x(node) {
+            return Ok(num("0"));
+        }
+        let op = |op, left, right| TreeNode::Op(op, Box::new(left), Box::new(right));
+        let derivative = match node {
+            TreeNode::Var(_) => num("1"),
+            TreeNode::Op(o @ ('+' | '-'), a, b) => op(*o, self.derive(a)?, self.derive(b)?),
+            TreeNode::Op('*', a, b) => op(
+                '+',
+                op('*', self.derive(a)?, *b.clone()),
+                op('*', *a.clone(), self.derive(b)?),
+            ),
+            TreeNode::Op('/', a, b) => op(
+                '/',
+                op(
+                    '-',
+                    op('*', self.derive(a)?, *b.clone()),
+                    op('*', *a.clone(), self.derive(b)?),
+                ),
+                op('^', *b.clone(), num("2")),
+            ),
+            TreeNode::Op('^', a, b) if !self.depends_on_x(b) => op(
+                '*',
+                op(
+                    '*',
+                    *b.clone(),
+                    op('^', *a.clone(), op('-', *b.clone(), num("1"))),
+                ),
+                self.derive(a)?,
+            ),
+            // (a^b)' = a^b*(b'*ln(a) + b*a'/a)
+            TreeNode::Op('^', a, b) => {
+                if !self.program.definitions.iter().any(|t| t.name == "ln") {
+                    return Err(format!(
+                        "The derivative of {} needs a definition of ln(x)",
+                        create_expression(node.clone())
+                    ));
+                }
+                let ln = TreeNode::Fun("ln".to_string(), 1, a.clone());
+                op(
+                    '*',
+                    node.clone(),
+                    op(
+                        '+',
+                        op('*', self.derive(b)?, ln),
+                        op('/', op('*', *b.clone(), self.derive(a)?), *a.clone()),
+                    ),
+                )
+            }
+            TreeNode::Fun(name, iterate, arg) => {
+                let derived = self.derived_function(name)?;
+                let mut inner = *arg.clone();
+                let mut result = self.derive(arg)?;
+                for _ in 0..*iterate {
+                    if let TreeNode::Op(..) = inner {
+                        inner = TreeNode::Paren(Box::new(inner));
+                    }
+                    result = op('*', replace_x(&derived, &inner), result);
+                    inner = TreeNode::Fun(name.clone(), 1, Box::new(inner));
+                }
+                result
+            }
+            TreeNode::Iterate(name, count, arg) => {
+                let iterate = iteration_count(name, count, &self.program.definitions, false)?;
+                self.derive(&TreeNode::Fun(name.clone(), iterate, arg.clone()))?
+            }
+            TreeNode::Piecewise(..) => self.derive(&lower_piecewise(node))?,
+            TreeNode::Series(..) => self.derive(&expand_series(node))?,
+            TreeNode::Paren(expr) => self.derive(expr)?,
+            TreeNode::Op(..) | TreeNode::Num(_) | TreeNode::Empty => num("0"),
+        };
+        Ok(simplify(&derivative))
+    }
+
+    /// The derivative of the definition `name`, computed once.
+    fn derived_function(&mut self, name: &str) -> Result<TreeNode, String> {
+        if let Some((_, derived)) = self.derived.iter().find(|(n, _)| n == name) {
+            return Ok(derived.clone());
+        }
+        let tree = self.program.definitions.iter().find(|t| t.name == name);
+        let derived = self.derive(&tree.unwrap().root_node)?;
+        self.derived.push((name.to_string(), derived.clone()));
+        Ok(derived)
+    }
+}
+
+fn num(n: &str) -> TreeNode {
+    TreeNode::Num(n.to_string())
+}
+
+/// Removes what the rules of differentiation leave behind: operations on numbers are computed
+/// if the result is exact, `e+0`, `e*1`, `e^1` and friends become `e`, `e*0` becomes 0,
+/// numbers move to the front of products, e.g. `x^2*3` becomes `3*x^2`, and `a - -2*b`
+/// becomes `a + 2*b`.
+pub fn simplify(node: &TreeNode) -> TreeNode {
+    let op = |op, left, right| TreeNode::Op(op, Box::new(left), Box::new(right));
+    let number = |node: &TreeNode| match node {
+        TreeNode::Num(n) => n.parse::<Dec>().ok(),
+        _ => None,
+    };
+    let is = |node: &TreeNode, n: &str| number(node) == n.parse::<Dec>().ok();
+    match node {
+        TreeNode::Op(o, left, right) => {
+            let (left, right) = (simplify(left), simplify(right));
+            if let (Some(a), Some(b)) = (number(&left), number(&right)) {
+                let result = match o {
+                    '+' => Some(a + b),
+                    '-' => Some(a - b),
+                    '*' => Some(a * b),
+                    '/' if b != zero() => Some(a.clone() / b.clone()).filter(|q| q * b == a),
+                    '^' if b.is_integer() && b >= zero() && b <= 64 => pow(a, b),
+                    _ => None,
+                };
+                if let Some(result) = result {
+                    return TreeNode::Num(trim2(result));
+                }
+            }
+            // a - -2*b is a + 2*b.
+            let negated = |node: &TreeNode| match node {
+                TreeNode::Num(n) => n.strip_prefix('-').map(num),
+                TreeNode::Op(o @ ('*' | '/'), factor, rest) => match &**factor {
+                    TreeNode::Num(n) => n.strip_prefix('-').map(|n| op(*o, num(n), *rest.clone())),
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let (o @ ('+' | '-'), Some(positive)) = (o, negated(&right)) {
+                return op(if *o == '+' { '-' } else { '+' }, left, positive);
+            }
+            match o {
+                '+' if is(&left, "0") => right,
+                '+' | '-' if is(&right, "0") => left,
+                // a + (0 - b) is a - b.
+                '+' => match right {
+                    TreeNode::Op('-', zero, b) if is(&zero, "0") => op('-', left, *b),
+                    right => op('+', left, right),
+                },
+                '*' if is(&left, "0") || is(&right, "0") => num("0"),
+                '*' if is(&left, "1") => right,
+                '*' if is(&right, "1") => left,
+                '*' if number(&right).is_some() && number(&left).is_none() => {
+                    simplify(&op('*', right, left))
+                }
+                // 2*(3*x) is 6*x.
+                '*' => match (&left, right) {
+                    (TreeNode::Num(_), TreeNode::Op('*', factor, rest))
+                        if number(&factor).is_some() =>
+                    {
+                        simplify(&op('*', op('*', left, *factor), *rest))
+                    }
+                    (_, right) => op('*', left, right),
+                },
+                '/' if is(&left, "0") => num("0"),
+                '/' | '^' if is(&right, "1") => left,
+                '^' if is(&right, "0") => num("1"),
+                o => op(*o, left, right),
+            }
+        }
+        TreeNode::Fun(name, iterate, arg) => {
+            TreeNode::Fun(name.clone(), *iterate, Box::new(simplify(arg)))
+        }
+        TreeNode::Paren(expr) => match simplify(expr) {
+            inner @ (TreeNode::Num(_)
+            | TreeNode::Var(_)
+            | TreeNode::Fun(..)
+            | TreeNode::Iterate(..)
+            | TreeNode::Paren(_)) => inner,
+            inner => TreeNode::Paren(Box::new(inner)),
+        },
+        _ => node.clone(),
+    }
+}
@@ -0,0 +1,90 @@
+//! The equivalence check of two definitions behind `equiv`.
+use super::*;
+use normal_form::RationalFunction;
+
+/// The result of `Program::equivalent`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Equivalence {
+    /// Both have this normal form. They may still differ where one of them is undefined, like
+    /// `(x^2-1)/(x-1)` and `x+1` at x=1, or `x^0` and `1` at x=0, since `0^0` is Undefined but
+    /// `x^0` normalizes to 1.
+    SameNormalForm(RationalFunction),
+    /// They agree at this many sample inputs.
+    SameSamples(usize),
+    /// They differ at `x`.
+    Counterexample { x: Dec, f: Value, g: Value },
+}
+
+impl Program {
+    /// Whether the definitions `f` and `g` compute the same function. If both have a normal form,
+    /// it decides. Otherwise, also if one is refused like for `2^5000`, they are compared at the
+    /// inputs of `sample_inputs`, numbers must agree to `digits` decimal places. NaN from a math
+    /// trick agrees with everything, but Undefined only with Undefined.
+    pub fn equivalent(
+        &self,
+        f: &str,
+        g: &str,
+        digits: usize,
+        use_math_tricks: bool,
+    ) -> Result<Equivalence, String> {
+        let (normal_f, normal_g) = (self.normal_form(f)?, self.normal_form(g)?);
+        if let (Some(normal_f), Some(normal_g)) = (&normal_f, &normal_g)
+            && normal_f == normal_g
+        {
+            return Ok(Equivalence::SameNormalForm(normal_f.clone()));
+        }
+        // Different normal forms differ almost everywhere, so any difference is a counterexample.
+        let tolerance: Dec = if normal_f.is_some() && normal_g.is_some() {
+            zero()
+        } else {
+            format!("1e-{digits}").parse().unwrap()
+        };
+        let definitions = self.fold_constants(use_math_tricks);
+        let value = |name: &str, x: &Dec| {
+            let call = TreeNode::Fun(
+                name.to_string(),
+                1,
+                Box::new(TreeNode::Num(trim2(x.clone()))),
+            );
+            apply_algebra_to_tree_node(&call, &zero(), &definitions, use_math_tricks)
+        };
+        let inputs = sample_inputs();
+        for x in &inputs {
+            let (f, g) = (value(f, x), value(g, x));
+            let same = match (&f, &g) {
+                (Value::Number(a), Value::Number(b)) => (a - b).abs() <= tolerance,
+                (Value::Undefined(_), Value::Undefined(_)) => true,
+                (Value::Indeterminate, _) | (_, Value::Indeterminate) => true,
+                _ => false,
+            };
+            if !same {
+                return Ok(Equivalence::Counterexample { x: x.clone(), f, g });
+            }
+        }
+        Ok(Equivalence::SameSamples(inputs.len()))
+    }
+}
+
+/// The integers from -20 to 20, ±100 and ±1000, `k ± tiny` and `k + NaN` for k from -3 to 3 with
+/// the `tiny` 10^-decimals of the math tricks and their NaN sentinel, and 60 pseudo-random
+/// numbers from -10 to 10 with three decimals.
+pub fn sample_inputs() -> Vec<Dec> {
+    let mut inputs: Vec<Dec> = (-20..=20)
+        .chain([-1000, -100, 100, 1000])
+        .map(Dec::from)
+        .collect();
+    let tiny: Dec = format!("1e-{}", get_decimal_places()).parse().unwrap();
+    let nan: Dec = get_nan().parse().unwrap();
+    for k in (-3..=3).map(Dec::from) {
+        inputs.extend([&k - &tiny, &k + &tiny, &k + &nan]);
+    }
+    let mut seed: u64 = 1;
+    for _ in 0..60 {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        let thousandths = (seed >> 33) % 20001;
+        inputs.push(Dec::from(thousandths as i64 - 10000) / Dec::from(1000));
+    }
+    inputs
+}
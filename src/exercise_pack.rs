@@ -0,0 +1,160 @@
+//! Exercise packs: ordered exercises in a JSON file, loaded as TestCases for `grade`.
+//!
+//! ```json
+//! {
+//!   "name": "From abs to left",
+//!   "exercises": [
+//!     {
+//!       "description": "Return the absolute value of x.",
+//!       "examples": [["-1", "1"], ["0", "0"]],
+//!       "hidden_examples": [["-0.0025", "0.0025"]],
+//!       "solution": "abs(x) = (x^2)^(1/2)",
+//!       "helpers": [],
+//!       "policy": {"operators": "+-*/^", "max_nodes": 10}
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Inputs and outputs of examples are constant expressions, an output may also be `NaN` or
+//! `Undefined`. `{decimals}` in examples and solutions is replaced by the decimal places, so
+//! `1-10^-{decimals}` is `0.99…9`. The last definition of `solution` is the function to write,
+//! it may call its own definitions and the `helpers`, functions of earlier exercises.
+//! `hidden_examples` and the `policy` are optional, see `Policy` for its fields.
+use super::*;
+
+/// The pack `grade curriculum` uses, the way from `decimals` and `abs` to `left`.
+pub const CURRICULUM: &str = include_str!("curriculum.json");
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExercisePack {
+    pub name: String,
+    pub exercises: Vec<Exercise>,
+}
+
+/// An exercise as written in a pack, see the module documentation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Exercise {
+    pub description: Option<String>,
+    pub examples: Vec<[String; 2]>,
+    #[serde(default)]
+    pub hidden_examples: Vec<[String; 2]>,
+    pub solution: String,
+    #[serde(default)]
+    pub helpers: Vec<String>,
+    #[serde(default)]
+    pub policy: policy::Policy,
+}
+
+/// The exercises of the pack in `json` for `decimals` decimal places. The solution of each
+/// TestCase starts with the definitions of its helpers and of the functions they call. Fails if
+/// an exercise calls a function that is no helper.
+pub fn load(json: &str, decimals: usize) -> Result<Vec<TestCase>, String> {
+    let pack: ExercisePack =
+        serde_json::from_str(json).map_err(|e| format!("Invalid exercise pack: {e}"))?;
+    let mut defined: Vec<BinaryAlgebraicExpressionTree> = Vec::new();
+    let mut test_cases = Vec::new();
+    for (i, exercise) in pack.exercises.iter().enumerate() {
+        let context = |e: String| format!("Exercise {}: {e}", i + 1);
+        let test_case = exercise.to_test_case(&defined, decimals).map_err(context)?;
+        for tree in &test_case.solution {
+            if !defined.iter().any(|t| t.name == tree.name) {
+                defined.push(tree.clone());
+            }
+        }
+        test_cases.push(test_case);
+    }
+    Ok(test_cases)
+}
+
+/// The bundled pack CURRICULUM with the current decimal places.
+pub fn curriculum() -> Vec<TestCase> {
+    load(CURRICULUM, get_decimal_places()).expect("The curriculum is valid")
+}
+
+impl Exercise {
+    /// `defined` are the functions of the earlier exercises.
+    fn to_test_case(
+        &self,
+        defined: &[BinaryAlgebraicExpressionTree],
+        decimals: usize,
+    ) -> Result<TestCase, String> {
+        let solution = self.solution.replace("{decimals}", &decimals.to_string());
+        let own = parse_program(&solution)
+            .map_err(|errors| {
+                errors
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })?
+            .definitions;
+        if own.is_empty() {
+            return Err("The solution has no definition".to_string());
+        }
+        let mut called = Vec::new();
+        for tree in &own {
+            function_names(&tree.root_node, &mut called);
+        }
+        if let Some(name) = called
+            .iter()
+            .find(|n| !own.iter().any(|t| &&t.name == n) && !self.helpers.contains(n))
+        {
+            return Err(format!("The solution calls {name}, which is no helper"));
+        }
+        // The helpers and everything they call, in the order of definition.
+        let mut needed = self.helpers.clone();
+        for tree in defined.iter().rev() {
+            if needed.contains(&tree.name) {
+                function_names(&tree.root_node, &mut needed);
+            }
+        }
+        if let Some(name) = needed
+            .iter()
+            .find(|n| !defined.iter().any(|t| &&t.name == n))
+        {
+            return Err(format!(
+                "The helper {name} is no function of an earlier exercise"
+            ));
+        }
+        let mut definitions: Vec<BinaryAlgebraicExpressionTree> = defined
+            .iter()
+            .filter(|t| needed.contains(&t.name))
+            .cloned()
+            .collect();
+        definitions.extend(own);
+        let examples = |examples: &[[String; 2]]| {
+            examples
+                .iter()
+                .map(|[input, output]| {
+                    let output = match output.as_str() {
+                        "NaN" | "Undefined" => output.clone(),
+                        _ => evaluate(output, decimals)?,
+                    };
+                    Ok([evaluate(input, decimals)?, output])
+                })
+                .collect::<Result<Vec<_>, String>>()
+        };
+        Ok(TestCase {
+            description: self.description.clone(),
+            examples: examples(&self.examples)?,
+            hidden_examples: examples(&self.hidden_examples)?,
+            solution: definitions,
+            helpers: self.helpers.clone(),
+            policy: self.policy.clone(),
+        })
+    }
+}
+
+/// The exact value of the constant expression `text`.
+fn evaluate(text: &str, decimals: usize) -> Result<String, String> {
+    let text = text.replace("{decimals}", &decimals.to_string());
+    let program = parse_program(&format!("example = {text}"))
+        .map_err(|_| format!("Invalid example {text}"))?;
+    match apply_algebra_to_tree_node(&program.definitions[0].root_node, &zero(), &vec![], false) {
+        Value::Number(n) => Ok(trim2(n)),
+        value => Err(format!("The example {text} is {value}")),
+    }
+}
@@ -0,0 +1,160 @@
+//! The source formatter behind `fmt`.
+use super::*;
+
+/// Options of format_source.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FormatOptions {
+    /// Wraps lines longer than this after a `+` or `-`. Continued lines are indented.
+    pub max_width: Option<usize>,
+}
+
+/// Formats a whole program: one definition or query per line, printed by create_expression_styled
+/// with spaces around `+` and `-`. Comments are kept, runs of blank lines become one blank line.
+pub fn format_source(src: &str, options: FormatOptions) -> Result<String, Vec<ParseError>> {
+    parse_program(src)?;
+    let mut lines: Vec<String> = Vec::new();
+    let mut code = String::new();
+    let mut comments: Vec<String> = Vec::new();
+    let mut blank_line = false;
+    for raw_line in src.lines() {
+        let (code_part, comment) = match raw_line.split_once('#') {
+            Some((code_part, comment)) => (code_part, Some(format!("#{}", comment.trim_end()))),
+            None => (raw_line, None),
+        };
+        if code.is_empty() && code_part.trim().is_empty() {
+            match comment {
+                Some(comment) => {
+                    if std::mem::take(&mut blank_line) {
+                        lines.push(String::new());
+                    }
+                    lines.push(comment);
+                }
+                None => blank_line = !lines.is_empty(),
+            }
+            continue;
+        }
+        code += code_part;
+        code.push(' ');
+        comments.extend(comment);
+        if continues_on_next_line(&code) {
+            continue;
+        }
+        if std::mem::take(&mut blank_line) {
+            lines.push(String::new());
+        }
+        let trailing_comment = comments.pop();
+        lines.append(&mut comments);
+        let mut statement = wrap(&format_statement(&code)?, options.max_width);
+        if let (Some(comment), Some(last)) = (trailing_comment, statement.last_mut()) {
+            *last = format!("{last}  {comment}");
+        }
+        lines.append(&mut statement);
+        code.clear();
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+/// Formats a single definition or query.
+fn format_statement(code: &str) -> Result<String, Vec<ParseError>> {
+    let style = ExpressionStyle {
+        spaces: true,
+        ..Default::default()
+    };
+    let line = &split_source(code)[0];
+    if import::is_import(line) {
+        let path = import::parse_import(line).map_err(|e| vec![e])?;
+        Ok(format!("import \"{path}\""))
+    } else if is_use(line) {
+        prelude::parse_use(line).map_err(|e| vec![e])?;
+        Ok(line.text.split_whitespace().collect::<Vec<_>>().join(" "))
+    } else if is_definition(line) {
+        let tree = parse_definition(line)?;
+        let (head, body) = line.text.split_once('=').unwrap();
+        if is_point_free(body) {
+            let style = ExpressionStyle {
+                composition: true,
+                unicode: body.contains('∘'),
+                ..style
+            };
+            let body = create_expression_styled(tree.root_node, style);
+            return Ok(format!("{} = {body}", tree.name));
+        }
+        let body = create_expression_styled(keep_bare_constants(tree.root_node, code), style);
+        if head.contains('(') {
+            Ok(format!("{}(x) = {body}", tree.name))
+        } else {
+            Ok(format!("{} = {body}", tree.name))
+        }
+    } else {
+        let query = match parse_query(line)? {
+            Query::Call { name, input } => Query::Call {
+                name,
+                input: keep_bare_constants(input, code),
+            },
+            Query::Range {
+                name,
+                start,
+                end,
+                step,
+            } => Query::Range {
+                name,
+                start: keep_bare_constants(start, code),
+                end: keep_bare_constants(end, code),
+                step: keep_bare_constants(step, code),
+            },
+        };
+        Ok(query.to_string_styled(style))
+    }
+}
+
+/// Keeps the constants `code` uses without an argument, like `tiny`, from being printed as
+/// `tiny(0)`. They become variables, which print as their name.
+fn keep_bare_constants(node: TreeNode, code: &str) -> TreeNode {
+    parser_notes(code, |p| p.constants)
+        .into_iter()
+        .fold(node, |node, bare| {
+            replace_constant(&node, &bare.message, &TreeNode::Var(bare.message.clone()))
+        })
+}
+
+/// Breaks after ` + ` or ` - ` so that every line ends with an operator and continues.
+fn wrap(line: &str, max_width: Option<usize>) -> Vec<String> {
+    const INDENT: &str = "    ";
+    let Some(max_width) = max_width else {
+        return vec![line.to_string()];
+    };
+    let mut lines = Vec::new();
+    let mut rest = line;
+    loop {
+        let width = if lines.is_empty() {
+            max_width
+        } else {
+            max_width.saturating_sub(INDENT.len())
+        };
+        let breaks: Vec<usize> = rest
+            .match_indices([' '])
+            .map(|(i, _)| i)
+            .filter(|&i| rest[i..].starts_with(" + ") || rest[i..].starts_with(" - "))
+            .map(|i| i + 2)
+            .collect();
+        let break_at = breaks
+            .iter()
+            .rev()
+            .find(|&&i| i <= width)
+            .or(breaks.first());
+        match break_at {
+            Some(&i) if rest.len() > width => {
+                lines.push(rest[..i].to_string());
+                rest = rest[i..].trim_start();
+            }
+            _ => {
+                lines.push(rest.to_string());
+                break;
+            }
+        }
+    }
+    for line in lines.iter_mut().skip(1) {
+        line.insert_str(0, INDENT);
+    }
+    lines
+}
@@ -0,0 +1,244 @@
+//! Grading programs against exercises, behind `grade`. Everything works on strings, so the
+//! playground can grade without files.
+use super::*;
+
+/// How `grade` compares results. Programs are always evaluated without the math tricks, they
+/// would replace functions like `abs` by name and grade the built-in instead of the program.
+#[derive(Debug, Clone, Default)]
+pub struct GradeOptions {
+    /// A number passes if it differs from the expected number by at most this much.
+    pub tolerance: Dec,
+}
+
+/// An example of an exercise with the output of the graded program.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExampleResult {
+    pub input: String,
+    pub expected: String,
+    pub actual: String,
+    pub passed: bool,
+    /// A hidden example, input and outputs are empty.
+    pub hidden: bool,
+    /// `actual - expected` if both are numbers and differ.
+    pub difference: Option<String>,
+}
+
+/// The results of the examples of a TestCase.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExerciseReport {
+    /// The name of the last definition of the solution.
+    pub function: String,
+    pub description: Option<String>,
+    pub examples: Vec<ExampleResult>,
+}
+
+impl ExerciseReport {
+    pub fn passed(&self) -> bool {
+        self.examples.iter().all(|e| e.passed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub exercises: Vec<ExerciseReport>,
+    /// Passed examples.
+    pub passed: usize,
+    /// All examples.
+    pub total: usize,
+}
+
+impl Report {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// One line per exercise and per failed example, then the score.
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for exercise in &self.exercises {
+            let passed = exercise.examples.iter().filter(|e| e.passed).count();
+            writeln!(
+                f,
+                "{}: {passed}/{} passed",
+                exercise.function,
+                exercise.examples.len()
+            )?;
+            for example in exercise.examples.iter().filter(|e| !e.passed) {
+                if example.hidden {
+                    writeln!(f, "  {}: a hidden example failed", exercise.function)?;
+                    continue;
+                }
+                write!(
+                    f,
+                    "  {}({}): expected {}, got {}",
+                    exercise.function, example.input, example.expected, example.actual
+                )?;
+                match &example.difference {
+                    Some(difference) => writeln!(f, " (off by {difference})")?,
+                    None => writeln!(f)?,
+                }
+            }
+        }
+        let solved = self.exercises.iter().filter(|e| e.passed()).count();
+        write!(
+            f,
+            "Score: {}/{} examples, {solved}/{} exercises",
+            self.passed,
+            self.total,
+            self.exercises.len()
+        )
+    }
+}
+
+/// Reads exercises in the JSON format serde derives for `Vec<TestCase>`.
+pub fn load_exercises(json: &str) -> Result<Vec<TestCase>, String> {
+    serde_json::from_str(json).map_err(|e| format!("Invalid exercises: {e}"))
+}
+
+/// Evaluates the function of every exercise in the program `src` at the inputs of the examples.
+/// The helpers of an exercise the program does not define are added from the solution. An
+/// expected number passes within the tolerance, anything else like `NaN` must match the output
+/// exactly, except that `Undefined` matches every Undefined value. If the function is missing,
+/// breaks the policy of the exercise or calls a function that the program does not define and
+/// that is no helper, every example fails. Fails only if `src` does not parse.
+pub fn grade(exercises: &[TestCase], src: &str, options: &GradeOptions) -> Result<Report, String> {
+    let program = parse_program(src).map_err(|errors| {
+        errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    })?;
+    let mut reports = Vec::new();
+    for exercise in exercises {
+        let function = exercise
+            .solution
+            .last()
+            .ok_or("An exercise has no solution")?
+            .name
+            .clone();
+        let examples = exercise
+            .examples
+            .iter()
+            .map(|example| (example, false))
+            .chain(
+                exercise
+                    .hidden_examples
+                    .iter()
+                    .map(|example| (example, true)),
+            );
+        let definitions = exercise_definitions(&program, exercise, &function);
+        let examples = examples
+            .map(|([input, expected], hidden)| {
+                let result = match &definitions {
+                    Ok(definitions) => {
+                        let call =
+                            TreeNode::Fun(function.clone(), 1, Box::new(parse_expression(input)));
+                        let value = apply_algebra_to_tree_node(&call, &zero(), definitions, false);
+                        compare(input, expected, value, &options.tolerance)
+                    }
+                    Err(e) => failed(input, expected, e.clone()),
+                };
+                if hidden {
+                    return ExampleResult {
+                        hidden,
+                        passed: result.passed,
+                        ..failed("", "", String::new())
+                    };
+                }
+                result
+            })
+            .collect();
+        reports.push(ExerciseReport {
+            function,
+            description: exercise.description.clone(),
+            examples,
+        });
+    }
+    let examples = reports.iter().flat_map(|r| &r.examples);
+    Ok(Report {
+        passed: examples.clone().filter(|e| e.passed).count(),
+        total: examples.count(),
+        exercises: reports,
+    })
+}
+
+/// The definitions of `program` and the helpers it needs for `exercise`, constants folded.
+fn exercise_definitions(
+    program: &Program,
+    exercise: &TestCase,
+    function: &str,
+) -> Result<Vec<BinaryAlgebraicExpressionTree>, String> {
+    if !program.definitions.iter().any(|t| t.name == function) {
+        return Err(format!("Function {function} not defined"));
+    }
+    // The definitions of the program the function needs.
+    let mut needed = vec![function.to_string()];
+    let mut i = 0;
+    while i < needed.len() {
+        if let Some(tree) = program.definitions.iter().find(|t| t.name == needed[i]) {
+            function_names(&tree.root_node, &mut needed);
+        } else if !exercise.helpers.contains(&needed[i]) {
+            return Err(format!("Function {} not defined", needed[i]));
+        }
+        i += 1;
+    }
+    let mut definitions: Vec<BinaryAlgebraicExpressionTree> = program
+        .definitions
+        .iter()
+        .filter(|t| needed.contains(&t.name))
+        .cloned()
+        .collect();
+    // The policy applies to everything the program defines, not to the helpers added below.
+    let violations = exercise.policy.check(&definitions, function);
+    if !violations.is_empty() {
+        return Err(violations.join("; "));
+    }
+    let is_defined = |name: &String| definitions.iter().any(|t| &t.name == name);
+    let helpers: Vec<BinaryAlgebraicExpressionTree> = exercise
+        .solution
+        .iter()
+        .filter(|t| t.name != function && !is_defined(&t.name))
+        .cloned()
+        .collect();
+    definitions.extend(helpers);
+    let program = Program {
+        definitions,
+        queries: Vec::new(),
+    };
+    let errors = program.check();
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    Ok(program.fold_constants(false))
+}
+
+fn failed(input: &str, expected: &str, actual: String) -> ExampleResult {
+    ExampleResult {
+        input: input.to_string(),
+        expected: expected.to_string(),
+        actual,
+        passed: false,
+        hidden: false,
+        difference: None,
+    }
+}
+
+fn compare(input: &str, expected: &str, value: Value, tolerance: &Dec) -> ExampleResult {
+    let actual = value.to_string();
+    let (passed, difference) = match (&value, expected.parse::<Dec>()) {
+        (Value::Number(a), Ok(e)) => {
+            let difference = a - e;
+            let passed = difference.abs() <= *tolerance;
+            (passed, (difference != zero()).then(|| trim2(difference)))
+        }
+        (Value::Undefined(_), _) if expected == "Undefined" => (true, None),
+        _ => (actual == expected, None),
+    };
+    ExampleResult {
+        passed,
+        difference,
+        ..failed(input, expected, actual)
+    }
+}
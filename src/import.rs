@@ -0,0 +1,170 @@
+//! `import "file.sva"` statements. They are resolved before parsing: the definitions of the
+//! imported files are appended to the program, so positions in the importing file stay valid.
+use super::*;
+use std::path::{Path, PathBuf};
+
+/// Whether `line` is a statement like `import "tape.sva"`.
+pub(crate) fn is_import(line: &SourceLine) -> bool {
+    !is_definition(line) && line.text.split_whitespace().next() == Some("import")
+}
+
+/// The path of `import "path"`.
+pub(crate) fn parse_import(line: &SourceLine) -> Result<&str, ParseError> {
+    let indent = line.text.chars().take_while(|c| c.is_whitespace()).count();
+    line.text
+        .trim()
+        .strip_prefix("import")
+        .map(str::trim)
+        .and_then(|path| path.strip_prefix('"'))
+        .and_then(|path| path.strip_suffix('"'))
+        .filter(|path| !path.is_empty() && !path.contains('"'))
+        .ok_or(line.error(indent, "Expected import \"file.sva\"".to_string()))
+}
+
+/// Replaces every import in `src` by nothing and appends the definitions and `use` statements of
+/// the imported files, recursively. Paths are relative to the directory of the importing file,
+/// `file` for `src` or the current directory without a file. Queries of imported files are
+/// dropped and a file imported twice is included once. Fails on import cycles and on a function
+/// defined in two files.
+pub fn resolve_imports(src: &str, file: Option<&Path>) -> Result<String, String> {
+    let mut resolver = Resolver::default();
+    resolver
+        .stack
+        .extend(file.and_then(|f| f.canonicalize().ok()));
+    let dir = file.and_then(Path::parent).unwrap_or(Path::new(""));
+    let mut lines: Vec<String> = src.lines().map(str::to_string).collect();
+    for line in split_source(src) {
+        if is_import(&line) {
+            for position in &line.positions {
+                lines[position.line - 1].clear();
+            }
+        }
+    }
+    let mut appended = String::new();
+    resolver.include(src, file, dir, false, &mut appended)?;
+    if appended.is_empty() {
+        return Ok(src.to_string());
+    }
+    Ok(lines.join("\n") + "\n" + &appended)
+}
+
+/// Resolves the imports, `use prelude`, constants and iteration counts. The result is a
+/// self-contained program in pure SVA: the definitions, then the queries, without comments.
+pub fn flatten(src: &str, file: Option<&Path>) -> Result<String, String> {
+    let program = parse_program(&resolve_imports(src, file)?).map_err(|errors| {
+        errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    })?;
+    let style = ExpressionStyle {
+        spaces: true,
+        ..Default::default()
+    };
+    let definitions = program.definitions.into_iter().map(|tree| {
+        format!(
+            "{}(x) = {}",
+            tree.name,
+            create_expression_styled(tree.root_node, style)
+        )
+    });
+    let queries = program.queries.iter().map(|q| q.to_string_styled(style));
+    Ok(definitions.chain(queries).collect::<Vec<_>>().join("\n") + "\n")
+}
+
+#[derive(Default)]
+struct Resolver {
+    /// The files that are being included, to find cycles.
+    stack: Vec<PathBuf>,
+    included: Vec<PathBuf>,
+    /// Every function with the file that defines it.
+    origins: Vec<(String, String)>,
+    uses: Vec<String>,
+}
+
+impl Resolver {
+    /// Appends the definitions of the file `path` to `out`. The importing program itself is not
+    /// `imported`, its statements are already in place.
+    fn include(
+        &mut self,
+        src: &str,
+        path: Option<&Path>,
+        dir: &Path,
+        imported: bool,
+        out: &mut String,
+    ) -> Result<(), String> {
+        let file = path.map_or("the program".to_string(), |p| p.display().to_string());
+        let lines = split_source(src);
+        let mut imports = Vec::new();
+        let mut code = Vec::new();
+        for line in &lines {
+            if is_import(line) {
+                let import = parse_import(line).map_err(|e| format!("{file}: {e}"))?;
+                imports.push(dir.join(import));
+            } else {
+                code.push(line.text.as_str());
+            }
+        }
+        if imported {
+            parse_program(&code.join("\n")).map_err(|errors| {
+                errors
+                    .iter()
+                    .map(|e| format!("{file}: {e}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })?;
+        }
+        for line in lines.iter().filter(|l| !is_import(l)) {
+            if is_use(line) {
+                let statement = line.text.split_whitespace().collect::<Vec<_>>().join(" ");
+                if !self.uses.contains(&statement) && imported {
+                    out.push_str(&statement);
+                    out.push('\n');
+                }
+                self.uses.push(statement);
+            } else if is_definition(line) {
+                let name = parse_function(&line.text).unwrap().0.trim().to_string();
+                let mut origins = self.origins.iter();
+                if let Some((_, other)) = origins.find(|(n, f)| n == &name && f != &file) {
+                    return Err(format!("Function {name} is defined in {other} and {file}"));
+                }
+                self.origins.push((name, file.clone()));
+                if imported {
+                    out.push_str(line.text.trim());
+                    out.push('\n');
+                }
+            }
+        }
+        for import in imports {
+            let canonical = import
+                .canonicalize()
+                .map_err(|e| format!("{file}: Cannot read {}: {e}", import.display()))?;
+            if self.stack.contains(&canonical) {
+                let cycle: Vec<String> = self
+                    .stack
+                    .iter()
+                    .chain([&canonical])
+                    .map(|p| {
+                        p.file_name()
+                            .unwrap_or_default()
+                            .to_string_lossy()
+                            .to_string()
+                    })
+                    .collect();
+                return Err(format!("Import cycle: {}", cycle.join(" -> ")));
+            }
+            if self.included.contains(&canonical) {
+                continue;
+            }
+            self.included.push(canonical.clone());
+            let src = std::fs::read_to_string(&canonical)
+                .map_err(|e| format!("{file}: Cannot read {}: {e}", import.display()))?;
+            self.stack.push(canonical);
+            let dir = import.parent().unwrap_or(Path::new(""));
+            self.include(&src, Some(&import), dir, true, out)?;
+            self.stack.pop();
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,32 @@
 use serde::{Deserialize, Serialize};
 use std::io::{self, Read};
-use std::sync::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+mod cli;
 mod decimal_crate;
 use decimal_crate::*;
+pub mod derivative;
+pub mod equivalence;
+pub mod exercise_pack;
+pub mod formatter;
+pub mod grader;
+pub mod import;
+pub mod normal_form;
+pub mod policy;
+pub mod prelude;
+mod repl;
+pub mod turing_machine;
 
-static DECIMAL_PLACES: OnceLock<usize> = OnceLock::new();
-static NAN: OnceLock<String> = OnceLock::new();
+static DECIMAL_PLACES: AtomicUsize = AtomicUsize::new(MAX_DECIMAL_PLACES);
+
+/// Upper bound for the number of values a single range query like `f(0..1 step 0.1)` produces.
+pub const MAX_TABLE_ROWS: usize = 100_000;
+
+/// Upper bound for the size of a definition after `Program::expand` inlined all function calls.
+pub const MAX_EXPANDED_NODES: usize = 1_000_000;
+
+/// Upper bound for the number of terms of a `sum` or `product`.
+pub const MAX_SERIES_TERMS: usize = 10_000;
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub enum TreeNode {
@@ -14,10 +34,100 @@ pub enum TreeNode {
     Num(String),
     Var(String),
     Fun(String, usize, Box<TreeNode>),
+    /// `name^[count](arg)` with a constant expression as count. parse_program replaces it by a
+    /// `Fun` as soon as all functions it calls are known.
+    Iterate(String, Box<TreeNode>, Box<TreeNode>),
+    /// `{ value if condition; ...; value otherwise }`. parse_program replaces it by products of
+    /// ge0, lt1 and is0, see lower_piecewise.
+    Piecewise(Vec<Case>, Box<TreeNode>),
+    /// `sum(k=from..to, term)` with the operator `+` or `product(k=from..to, term)` with `*`.
+    /// parse_program replaces it by the terms, see expand_series.
+    Series(char, String, i64, i64, Box<TreeNode>),
     Paren(Box<TreeNode>),
     Empty,
 }
 
+/// A case `value if left comparison right` of a piecewise expression.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Case {
+    pub value: TreeNode,
+    pub left: TreeNode,
+    /// One of `<`, `<=`, `>`, `>=` and `==`.
+    pub comparison: String,
+    pub right: TreeNode,
+}
+
+/// A position in a source file. Lines and columns start at 1 and count chars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A logical line of a program. Comments are removed and continued lines are joined, but every
+/// char remembers where it came from.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SourceLine {
+    pub text: String,
+    pub positions: Vec<Position>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub position: Position,
+    pub message: String,
+}
+
+/// An SVA program: function definitions and the queries that are evaluated against them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub definitions: Vec<BinaryAlgebraicExpressionTree>,
+    pub queries: Vec<Query>,
+}
+
+/// A line of a program that asks for a result instead of defining a function.
+/// Arguments are constant expressions and may call defined functions, e.g. `f(g(1)/3)`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    /// `f(2.5)`
+    Call { name: String, input: TreeNode },
+    /// `f(0..1 step 0.1)` evaluates `f` from `start` to `end` (inclusive). The step defaults to 1.
+    Range {
+        name: String,
+        start: TreeNode,
+        end: TreeNode,
+        step: TreeNode,
+    },
+}
+
+/// One row of the output of a program. The input is the argument as written for calls and the
+/// evaluated number for ranges.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryResult {
+    pub name: String,
+    pub input: String,
+    pub value: Value,
+}
+
+/// Options of create_expression_styled. The default is the compact style of create_expression.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ExpressionStyle {
+    /// Spaces around + and -, e.g. `3*x + 5`.
+    pub spaces: bool,
+    /// `·`, `÷`, `−`, superscript exponents and `√` for display, e.g. `3·x² − √x`.
+    pub unicode: bool,
+    /// Nested calls as compositions, e.g. `left.right` for `left(right(x))` and
+    /// `(left.right)(x+1)`, with `∘` instead of `.` in the unicode style.
+    pub composition: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Csv,
+    Json,
+}
+
 /// The main struct of this crate. A binary algebraic expression tree is a TreeNode.
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct BinaryAlgebraicExpressionTree {
@@ -25,13 +135,724 @@ pub struct BinaryAlgebraicExpressionTree {
     pub root_node: TreeNode,
 }
 
-/// A TestCase is useful for unit testing in `mod tests`.
+/// An exercise: write the last function of `solution`, so that it maps every input of `examples`
+/// to its output. `grader::grade` grades a program against exercises, `mod tests` checks the
+/// solutions.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TestCase {
     pub description: Option<String>,
     /// A vector full of examples. The first String is the input and the second the expected output.
     pub examples: Vec<[String; 2]>,
+    /// Graded like `examples`, but reports do not show them.
+    #[serde(default)]
+    pub hidden_examples: Vec<[String; 2]>,
+    /// The reference definitions, the helpers first.
     pub solution: Vec<BinaryAlgebraicExpressionTree>,
+    /// Functions of `solution` a program may call without defining them.
+    #[serde(default)]
+    pub helpers: Vec<String>,
+    /// What the definitions of a program may use.
+    #[serde(default)]
+    pub policy: policy::Policy,
+}
+
+/// The result of evaluating a TreeNode.
+///
+/// Propagation rules: an operator with an Undefined operand is Undefined (the left operand's
+/// reason wins), otherwise an operator with an Indeterminate operand is Indeterminate. There is
+/// no "very strongly zero", so `0*(1/0)` is Undefined. Iterating a function stops as soon as the
+/// value is no longer a Number.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(Dec),
+    /// The expression has no value, e.g. a division by zero. Contains the reason and location.
+    Undefined(String),
+    /// A math trick hit the NaN sentinel and cannot decide between its two neighbouring results.
+    Indeterminate,
+}
+
+impl Value {
+    pub fn is_number(&self) -> bool {
+        matches!(self, Value::Number(_))
+    }
+
+    /// Applies `f` if both values are numbers. Otherwise the propagation rules apply.
+    pub fn combine(self, other: Value, f: impl FnOnce(Dec, Dec) -> Value) -> Value {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => f(a, b),
+            (Value::Undefined(reason), _) | (_, Value::Undefined(reason)) => {
+                Value::Undefined(reason)
+            }
+            _ => Value::Indeterminate,
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{}", trim2(n.clone())),
+            Value::Undefined(reason) => write!(f, "Undefined ({reason})"),
+            Value::Indeterminate => write!(f, "NaN"),
+        }
+    }
+}
+
+impl Program {
+    /// Evaluates every query in order.
+    pub fn run(&self, use_math_tricks: bool) -> Result<Vec<QueryResult>, String> {
+        if self.queries.is_empty() {
+            return Err("No function call found".to_string());
+        }
+        let errors = self.check();
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+        let definitions = self.fold_constants(use_math_tricks);
+        let mut results = Vec::new();
+        for query in &self.queries {
+            let name = query.name();
+            let inputs = self.query_inputs(query, use_math_tricks)?;
+            if !self.definitions.iter().any(|t| t.name == name) {
+                return Err(format!("Function {name} not defined"));
+            }
+            for (text, input) in inputs {
+                // Evaluated as a call so that an Undefined result names its location.
+                let call = TreeNode::Fun(name.to_string(), 1, Box::new(input));
+                let value =
+                    apply_algebra_to_tree_node(&call, &zero(), &definitions, use_math_tricks);
+                results.push(QueryResult {
+                    name: name.to_string(),
+                    input: text,
+                    value,
+                });
+            }
+        }
+        Ok(results)
+    }
+
+    /// The inputs of a query as text and as a tree, without evaluating the function.
+    fn query_inputs(
+        &self,
+        query: &Query,
+        use_math_tricks: bool,
+    ) -> Result<Vec<(String, TreeNode)>, String> {
+        match query {
+            Query::Call { input, .. } => {
+                self.check_constant(input)?;
+                Ok(vec![(create_expression(input.clone()), input.clone())])
+            }
+            Query::Range {
+                name,
+                start,
+                end,
+                step,
+            } => {
+                let start = self.evaluate_constant(start, use_math_tricks)?;
+                let end = self.evaluate_constant(end, use_math_tricks)?;
+                let step = self.evaluate_constant(step, use_math_tricks)?;
+                if step <= zero() {
+                    return Err(format!("Step of {name} must be positive"));
+                }
+                // Counting the steps instead of adding them up keeps a step like 1/3 from
+                // missing the end. Inputs are rounded to the decimal places.
+                let decimals = get_decimal_places();
+                let steps = floor(round((&end - &start) / &step, decimals));
+                if steps >= MAX_TABLE_ROWS as u64 {
+                    return Err(format!(
+                        "Range of {name} has more than {MAX_TABLE_ROWS} values"
+                    ));
+                }
+                let count = match trim2(steps).parse::<usize>() {
+                    Ok(steps) => steps + 1,
+                    Err(_) => 0,
+                };
+                Ok((0..count)
+                    .map(|i| {
+                        let input = round(&start + &step * Dec::from(i as u64), decimals);
+                        let text = trim2(input);
+                        (text.clone(), TreeNode::Num(text))
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    /// Finds duplicate and recursive definitions and calls of undefined functions.
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (i, tree) in self.definitions.iter().enumerate() {
+            if self.definitions[..i].iter().any(|t| t.name == tree.name) {
+                errors.push(format!("Function {} is defined twice", tree.name));
+            }
+        }
+        let is_defined = |name: &String| self.definitions.iter().any(|t| &t.name == name);
+        for tree in &self.definitions {
+            let mut names = Vec::new();
+            function_names(&tree.root_node, &mut names);
+            for name in names.iter().filter(|name| !is_defined(name)) {
+                errors.push(format!(
+                    "Function {name} not defined, called in {}",
+                    tree.name
+                ));
+            }
+            if self.calls(&tree.name, &tree.name) {
+                errors.push(format!("Function {} calls itself", tree.name));
+            }
+        }
+        for query in &self.queries {
+            let name = query.name().to_string();
+            if !is_defined(&name) {
+                errors.push(format!("Function {name} not defined"));
+            }
+        }
+        errors
+    }
+
+    /// Whether `from` calls `to` directly or indirectly.
+    fn calls(&self, from: &str, to: &str) -> bool {
+        let mut visited: Vec<String> = Vec::new();
+        let mut stack = vec![from.to_string()];
+        while let Some(name) = stack.pop() {
+            let Some(tree) = self.definitions.iter().find(|t| t.name == name) else {
+                continue;
+            };
+            let mut names = Vec::new();
+            function_names(&tree.root_node, &mut names);
+            for called in names {
+                if called == to {
+                    return true;
+                }
+                if !visited.contains(&called) {
+                    visited.push(called.clone());
+                    stack.push(called);
+                }
+            }
+        }
+        false
+    }
+
+    /// Inlines every function call of the definition `name`, so the result only consists of
+    /// numbers, x and operators. Fails if the result would have more than MAX_EXPANDED_NODES nodes.
+    pub fn expand(&self, name: &str) -> Result<TreeNode, String> {
+        let errors = self.check();
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+        let tree = self
+            .definitions
+            .iter()
+            .find(|t| t.name == name)
+            .ok_or(format!("Function {name} not defined"))?;
+        let result = self.expand_node(&tree.root_node, &mut Vec::new())?;
+        if count_nodes(&result) > MAX_EXPANDED_NODES {
+            return Err(format!(
+                "The expansion of {name} has more than {MAX_EXPANDED_NODES} nodes"
+            ));
+        }
+        Ok(result)
+    }
+
+    /// `expanded` caches the expanded definitions.
+    fn expand_node(
+        &self,
+        node: &TreeNode,
+        expanded: &mut Vec<(String, TreeNode)>,
+    ) -> Result<TreeNode, String> {
+        Ok(match node {
+            TreeNode::Iterate(name, count, arg) => {
+                let iterate = iteration_count(name, count, &self.definitions, false)?;
+                let node = TreeNode::Fun(name.clone(), iterate, arg.clone());
+                self.expand_node(&node, expanded)?
+            }
+            TreeNode::Fun(name, iterate, arg) => {
+                let mut result = self.expand_node(arg, expanded)?;
+                if !expanded.iter().any(|(n, _)| n == name) {
+                    let tree = self.definitions.iter().find(|t| &t.name == name).unwrap();
+                    let body = self.expand_node(&tree.root_node, expanded)?;
+                    expanded.push((name.clone(), body));
+                }
+                let body = &expanded.iter().find(|(n, _)| n == name).unwrap().1;
+                for _ in 0..*iterate {
+                    if let TreeNode::Op(..) = result {
+                        result = TreeNode::Paren(Box::new(result));
+                    }
+                    result = replace_x(body, &result);
+                    if count_nodes(&result) > MAX_EXPANDED_NODES {
+                        return Err(format!(
+                            "The expansion of {name} has more than {MAX_EXPANDED_NODES} nodes"
+                        ));
+                    }
+                }
+                result
+            }
+            TreeNode::Op(op, left, right) => TreeNode::Op(
+                *op,
+                Box::new(self.expand_node(left, expanded)?),
+                Box::new(self.expand_node(right, expanded)?),
+            ),
+            TreeNode::Paren(expr) => TreeNode::Paren(Box::new(self.expand_node(expr, expanded)?)),
+            TreeNode::Piecewise(..) => self.expand_node(&lower_piecewise(node), expanded)?,
+            TreeNode::Series(..) => self.expand_node(&expand_series(node), expanded)?,
+            TreeNode::Num(_) | TreeNode::Var(_) | TreeNode::Empty => node.clone(),
+        })
+    }
+
+    /// The definitions that do not depend on x, like `decimals(x) = 50` or
+    /// `tiny(x) = 10^(-decimals(x))`, in an order in which they can be evaluated. Calls of
+    /// undefined functions count as constant.
+    pub fn constants(&self) -> Vec<String> {
+        let mut constants: Vec<String> = Vec::new();
+        loop {
+            let found = self.definitions.iter().find(|t| {
+                !constants.contains(&t.name) && !self.depends_on_x(&t.root_node, &constants)
+            });
+            match found {
+                Some(tree) => constants.push(tree.name.clone()),
+                None => return constants,
+            }
+        }
+    }
+
+    fn depends_on_x(&self, node: &TreeNode, constants: &[String]) -> bool {
+        match node {
+            TreeNode::Var(_) => true,
+            TreeNode::Fun(name, iterate, _) if *iterate > 0 && constants.contains(name) => false,
+            TreeNode::Fun(name, _, arg) | TreeNode::Iterate(name, _, arg) => {
+                self.definitions.iter().any(|t| &t.name == name)
+                    && self.depends_on_x(arg, constants)
+            }
+            TreeNode::Op(_, left, right) => {
+                self.depends_on_x(left, constants) || self.depends_on_x(right, constants)
+            }
+            TreeNode::Paren(expr) => self.depends_on_x(expr, constants),
+            TreeNode::Piecewise(..) => self.depends_on_x(&lower_piecewise(node), constants),
+            TreeNode::Series(..) => self.depends_on_x(&expand_series(node), constants),
+            TreeNode::Num(_) | TreeNode::Empty => false,
+        }
+    }
+
+    /// Replaces the body of every constant by its value, so it is evaluated once per run.
+    fn fold_constants(&self, use_math_tricks: bool) -> Vec<BinaryAlgebraicExpressionTree> {
+        let mut definitions = self.definitions.clone();
+        for name in self.constants() {
+            let i = definitions.iter().position(|t| t.name == name).unwrap();
+            let value = apply_algebra_to_tree_node(
+                &definitions[i].root_node,
+                &zero(),
+                &definitions,
+                use_math_tricks,
+            );
+            // An undefined constant keeps its body, so the error names its location.
+            if let Value::Number(n) = value {
+                definitions[i].root_node = TreeNode::Num(trim2(n));
+            }
+        }
+        definitions
+    }
+
+    /// Replaces every `name^[count](arg)` with a constant count by a Fun.
+    fn resolve_iterations(&self, node: &TreeNode) -> Result<TreeNode, String> {
+        Ok(match node {
+            TreeNode::Iterate(name, count, arg) => TreeNode::Fun(
+                name.clone(),
+                iteration_count(name, count, &self.definitions, false)?,
+                Box::new(self.resolve_iterations(arg)?),
+            ),
+            TreeNode::Fun(name, iterate, arg) => TreeNode::Fun(
+                name.clone(),
+                *iterate,
+                Box::new(self.resolve_iterations(arg)?),
+            ),
+            TreeNode::Op(op, left, right) => TreeNode::Op(
+                *op,
+                Box::new(self.resolve_iterations(left)?),
+                Box::new(self.resolve_iterations(right)?),
+            ),
+            TreeNode::Paren(expr) => TreeNode::Paren(Box::new(self.resolve_iterations(expr)?)),
+            TreeNode::Piecewise(..) => self.resolve_iterations(&lower_piecewise(node))?,
+            TreeNode::Series(..) => self.resolve_iterations(&expand_series(node))?,
+            TreeNode::Num(_) | TreeNode::Var(_) | TreeNode::Empty => node.clone(),
+        })
+    }
+
+    /// A query argument must not contain `x` and may only call defined functions.
+    fn check_constant(&self, node: &TreeNode) -> Result<(), String> {
+        let mut names = Vec::new();
+        function_names(node, &mut names);
+        if let Some(name) = names
+            .iter()
+            .find(|name| !self.definitions.iter().any(|t| &&t.name == name))
+        {
+            return Err(format!("Function {name} not defined"));
+        }
+        if contains_node(node, &|n| matches!(n, TreeNode::Var(_))) {
+            return Err(format!(
+                "Invalid input value: {}",
+                create_expression(node.clone())
+            ));
+        }
+        Ok(())
+    }
+
+    fn evaluate_constant(&self, node: &TreeNode, use_math_tricks: bool) -> Result<Dec, String> {
+        self.check_constant(node)?;
+        match apply_algebra_to_tree_node(node, &zero(), &self.definitions, use_math_tricks) {
+            Value::Number(n) => Ok(n),
+            value => Err(format!(
+                "Input value {} is {value}",
+                create_expression(node.clone())
+            )),
+        }
+    }
+}
+
+/// Renders the results of a program. A program with a single call prints only its value as text.
+pub fn format_results(results: &[QueryResult], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Text => {
+            if let [result] = results {
+                return result.value.to_string();
+            }
+            results
+                .iter()
+                .map(|r| format!("{}({}) = {}", r.name, r.input, r.value))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        OutputFormat::Csv => {
+            let escape = |s: String| {
+                if s.contains(',') || s.contains('"') {
+                    format!("\"{}\"", s.replace('"', "\"\""))
+                } else {
+                    s
+                }
+            };
+            let mut lines = vec!["function,x,value".to_string()];
+            for r in results {
+                lines.push(format!(
+                    "{},{},{}",
+                    escape(r.name.clone()),
+                    escape(r.input.clone()),
+                    escape(r.value.to_string())
+                ));
+            }
+            lines.join("\n")
+        }
+        OutputFormat::Json => {
+            let rows: Vec<_> = results
+                .iter()
+                .map(|r| {
+                    let (kind, value) = match &r.value {
+                        Value::Number(_) => ("number", r.value.to_string()),
+                        Value::Undefined(reason) => ("undefined", reason.clone()),
+                        Value::Indeterminate => ("indeterminate", r.value.to_string()),
+                    };
+                    serde_json::json!({
+                        "function": r.name,
+                        "x": r.input,
+                        "kind": kind,
+                        "value": value,
+                    })
+                })
+                .collect();
+            serde_json::to_string_pretty(&rows).unwrap()
+        }
+    }
+}
+
+impl Query {
+    /// The name of the function the query calls.
+    pub fn name(&self) -> &str {
+        match self {
+            Query::Call { name, .. } | Query::Range { name, .. } => name,
+        }
+    }
+
+    /// Converts the query back to a String like f(0..1 step 0.1).
+    pub fn to_string_styled(&self, style: ExpressionStyle) -> String {
+        let expr = |node: &TreeNode| create_expression_styled(node.clone(), style);
+        match self {
+            Query::Call { name, input } => format!("{name}({})", expr(input)),
+            Query::Range {
+                name,
+                start,
+                end,
+                step,
+            } => format!("{name}({}..{} step {})", expr(start), expr(end), expr(step)),
+        }
+    }
+}
+
+impl std::fmt::Display for Query {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_string_styled(ExpressionStyle::default()))
+    }
+}
+
+impl SourceLine {
+    /// The source position of the char at `offset`. Offsets behind the end point behind the last char.
+    pub fn position(&self, offset: usize) -> Position {
+        match self.positions.get(offset) {
+            Some(position) => *position,
+            None => self
+                .positions
+                .last()
+                .map_or(Position::default(), |p| Position {
+                    line: p.line,
+                    column: p.column + 1,
+                }),
+        }
+    }
+
+    /// The char offset of `part`, which must be a slice of `self.text`.
+    fn offset_of(&self, part: &str) -> usize {
+        let byte_offset = part.as_ptr() as usize - self.text.as_ptr() as usize;
+        self.text[..byte_offset].chars().count()
+    }
+
+    fn error(&self, offset: usize, message: String) -> ParseError {
+        ParseError {
+            position: self.position(offset),
+            message,
+        }
+    }
+
+    /// Parses `part`, a slice of `self.text`, and maps the errors to source positions.
+    fn parse_part(&self, part: &str) -> Result<TreeNode, Vec<ParseError>> {
+        let start = self.offset_of(part);
+        let (node, errors) = parse_expression_with_errors(part);
+        if errors.is_empty() {
+            return Ok(node);
+        }
+        Err(errors
+            .into_iter()
+            .map(|(offset, message)| self.error(start + offset, message))
+            .collect())
+    }
+}
+
+/// Finds the expressions whose meaning changed when `^` became right-associative and started to
+/// bind tighter than a unary minus, like `2^3^2` or `-x^2`. Lines that do not parse are skipped.
+pub fn migration_warnings(src: &str) -> Vec<ParseError> {
+    parser_notes(src, |p| p.warnings)
+}
+
+/// Finds implicit multiplications like `2x`, which strict mode forbids. Lines that do not parse
+/// are skipped.
+pub fn implicit_multiplications(src: &str) -> Vec<ParseError> {
+    parser_notes(src, |p| {
+        p.implicit
+            .into_iter()
+            .map(|offset| {
+                (
+                    offset,
+                    "Implicit multiplication, write * in strict mode".to_string(),
+                )
+            })
+            .collect()
+    })
+}
+
+/// Parses every expression of `src` and maps what `notes` takes from the parser to source
+/// positions.
+fn parser_notes(src: &str, notes: impl Fn(Parser) -> Vec<(usize, String)>) -> Vec<ParseError> {
+    let mut found = Vec::new();
+    for line in split_source(src) {
+        let parts: Vec<&str> = if is_definition(&line) {
+            parse_function(&line.text)
+                .map(|(_, expr)| expr)
+                .into_iter()
+                .collect()
+        } else {
+            parse_function_call(&line.text)
+                .map(|(_, input)| match split_range(input) {
+                    Some((start, end, step)) => [Some(start), Some(end), step]
+                        .into_iter()
+                        .flatten()
+                        .collect(),
+                    None => vec![input],
+                })
+                .unwrap_or_default()
+        };
+        for part in parts {
+            let start = line.offset_of(part);
+            let mut p = Parser::new(part);
+            parse_additive(&mut p);
+            found.extend(
+                notes(p)
+                    .into_iter()
+                    .map(|(offset, message)| line.error(start + offset, message)),
+            );
+        }
+    }
+    found
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.position.line, self.position.column, self.message
+        )
+    }
+}
+
+/// Removes `#` comments and blank lines and joins lines that end with an operator, a `=` or an
+/// unclosed parenthesis with the following line.
+pub fn split_source(src: &str) -> Vec<SourceLine> {
+    let mut lines = Vec::new();
+    let mut current = SourceLine::default();
+    for (line_index, raw_line) in src.lines().enumerate() {
+        let code = raw_line.split('#').next().unwrap_or_default();
+        if !current.text.is_empty() {
+            current.text.push(' ');
+            current.positions.push(Position {
+                line: line_index + 1,
+                column: 0,
+            });
+        }
+        for (column_index, c) in code.chars().enumerate() {
+            current.text.push(c);
+            current.positions.push(Position {
+                line: line_index + 1,
+                column: column_index + 1,
+            });
+        }
+        if !continues_on_next_line(&current.text) {
+            if !current.text.trim().is_empty() {
+                lines.push(current);
+            }
+            current = SourceLine::default();
+        }
+    }
+    if !current.text.trim().is_empty() {
+        lines.push(current);
+    }
+    lines
+}
+
+/// Whether a logical line without comments is incomplete and continues on the next line.
+fn continues_on_next_line(text: &str) -> bool {
+    let trimmed = text.trim_end();
+    let open_parens = trimmed.matches(['(', '[', '{']).count();
+    let closed_parens = trimmed.matches([')', ']', '}']).count();
+    trimmed.ends_with([
+        '+', '-', '*', '/', '^', '=', '(', '[', '{', ';', '×', '·', '÷', '−', '√', '∘',
+    ]) || open_parens > closed_parens
+}
+
+/// Whether `line` is a definition like `f(x) = ...` or `c = ...`: a name, maybe followed by
+/// parentheses, and a `=` outside of brackets. `f(sum(k=1..3, k))` is a query.
+fn is_definition(line: &SourceLine) -> bool {
+    let mut depth = 0;
+    let equals = line.text.char_indices().find(|&(_, c)| {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            _ => {}
+        }
+        c == '=' && depth == 0
+    });
+    let Some((equals, _)) = equals else {
+        return false;
+    };
+    let head = line.text[..equals].trim();
+    let name = head.split_once('(').map_or(head, |(name, _)| name).trim();
+    is_identifier(name) && (name == head || head.ends_with(')'))
+}
+
+/// Splits a program into definitions (see is_definition), `use prelude` statements and queries.
+/// All errors are collected. `import` statements are skipped, import::resolve_imports replaces
+/// them before.
+pub fn parse_program(src: &str) -> Result<Program, Vec<ParseError>> {
+    let mut program = Program {
+        definitions: Vec::new(),
+        queries: Vec::new(),
+    };
+    let mut errors = Vec::new();
+    let mut positions = Vec::new();
+    let mut declared_constants = Vec::new();
+    for line in split_source(src) {
+        let result = if import::is_import(&line) {
+            import::parse_import(&line).map(|_| ()).map_err(|e| vec![e])
+        } else if is_use(&line) {
+            prelude::parse_use(&line)
+                .map(|trees| {
+                    let indent = line.text.chars().take_while(|c| c.is_whitespace()).count();
+                    positions.extend(trees.iter().map(|_| line.position(indent)));
+                    program.definitions.extend(trees);
+                })
+                .map_err(|e| vec![e])
+        } else if is_definition(&line) {
+            parse_definition(&line).map(|mut tree| {
+                tree.root_node = lower_piecewise(&expand_series(&tree.root_node));
+                let indent = line.text.chars().take_while(|c| c.is_whitespace()).count();
+                positions.push(line.position(indent));
+                let (head, body) = line.text.split_once('=').unwrap();
+                if !head.contains('(') && !is_point_free(body) {
+                    declared_constants.push((line.position(indent), tree.name.clone()));
+                }
+                program.definitions.push(tree);
+            })
+        } else {
+            parse_query(&line).map(|query| program.queries.push(query))
+        };
+        if let Err(mut e) = result {
+            errors.append(&mut e);
+        }
+    }
+    // Iteration counts like right^[decimals(1)-1] need all definitions. They are left for
+    // check to report if a function is missing or calls itself.
+    if errors.is_empty() && program.check().is_empty() {
+        for (i, position) in positions.into_iter().enumerate() {
+            match program.resolve_iterations(&program.definitions[i].root_node) {
+                Ok(node) => program.definitions[i].root_node = node,
+                Err(message) => errors.push(ParseError { position, message }),
+            }
+        }
+    }
+    // `c = ...` and a bare `c` need c not to depend on x. Functions defined elsewhere, like in
+    // an earlier input of the REPL, are not checked here.
+    let constants = program.constants();
+    let is_function = |name: &String| {
+        program.definitions.iter().any(|t| &t.name == name) && !constants.contains(name)
+    };
+    for (position, name) in declared_constants
+        .iter()
+        .filter(|(_, name)| is_function(name))
+    {
+        errors.push(ParseError {
+            position: *position,
+            message: format!("Constant {name} depends on x, write {name}(x) = ..."),
+        });
+    }
+    let bare_names = parser_notes(src, |p| p.constants);
+    for e in bare_names.into_iter().filter(|e| is_function(&e.message)) {
+        errors.push(ParseError {
+            position: e.position,
+            message: format!("{} depends on x and needs an argument", e.message),
+        });
+    }
+    if errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Whether `expr` is a composition without an argument like `left ∘ right`, which defines a
+/// function like `h = left ∘ right` without naming x.
+fn is_point_free(expr: &str) -> bool {
+    let mut p = Parser::new(expr);
+    parse_additive(&mut p);
+    p.composition == Some((0, p.tokens.len()))
+}
+
+/// Whether `line` is a statement like `use prelude`.
+fn is_use(line: &SourceLine) -> bool {
+    !is_definition(line) && line.text.split_whitespace().next() == Some("use")
 }
 
 impl Default for BinaryAlgebraicExpressionTree {
@@ -44,11 +865,16 @@ impl Default for BinaryAlgebraicExpressionTree {
 }
 
 fn get_decimal_places() -> usize {
-    *DECIMAL_PLACES.get_or_init(|| MAX_DECIMAL_PLACES)
+    DECIMAL_PLACES.load(Ordering::Relaxed)
+}
+
+/// Sets the decimal places the math tricks assume, like `decimals(x) = k` in the first line.
+pub fn set_decimal_places(k: usize) {
+    DECIMAL_PLACES.store(k, Ordering::Relaxed);
 }
 
-fn get_nan() -> &'static String {
-    NAN.get_or_init(|| format!("-0.{}1", "0".repeat(get_decimal_places())))
+fn get_nan() -> String {
+    format!("-0.{}1", "0".repeat(get_decimal_places()))
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -91,73 +917,7 @@ fn read_args() -> Vec<String> {
 
 /// For CLI.
 pub fn read_input() {
-    let mut use_math_tricks = false;
-    let args: Vec<String> = read_args();
-    if args.len() == 1
-        && let Some(first_line) = args[0].trim().lines().next()
-        && first_line.trim().starts_with("decimals(x) =")
-    {
-        use_math_tricks = true;
-        if let Some(k_str) = first_line.trim().strip_prefix("decimals(x) =") {
-            let k_clean = k_str.split_whitespace().next().unwrap_or(k_str);
-            if let Ok(k) = k_clean.parse::<usize>() {
-                let _ = DECIMAL_PLACES.set(k);
-                let _ = NAN.set(format!("-0.{}1", "0".repeat(k)));
-            }
-        }
-    }
-    let input = if args.len() == 1 && args[0].contains('\n') {
-        args[0]
-            .split('\n')
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .map(|s| s.to_string())
-            .collect()
-    } else if args.is_empty() {
-        let mut buffer = String::new();
-        io::stdin().read_to_string(&mut buffer).unwrap();
-        buffer
-            .split('\n')
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .map(|s| s.to_string())
-            .collect()
-    } else {
-        args
-    };
-    if input.is_empty() {
-        println!(
-            "Usage:\n  single-variable-algebra-compiler FUNC1(x)=expr1 FUNC2(x)=expr2 ... FUNCN(INPUT)\nOR\n  echo 'F(x)=4+4\nG(x)=F(x)*2\nG(1)' | single-variable-algebra-compiler"
-        );
-        return;
-    }
-    let mut trees = Vec::new();
-    for arg in input.iter().take(input.len() - 1) {
-        if let Some((name, expr)) = parse_function(arg) {
-            let tree = BinaryAlgebraicExpressionTree {
-                name: name.to_string(),
-                root_node: parse_expression(expr),
-            };
-            trees.push(tree);
-        } else {
-            output(format!("Invalid function definition: {arg}"));
-            return;
-        }
-    }
-    if let Some((func_name, input_val)) = parse_function_call(input.last().unwrap()) {
-        if let Some(tree) = trees.iter().find(|t| t.name == func_name) {
-            let x: Dec = input_val.parse().unwrap_or_else(|_| {
-                output(format!("Invalid input value: {input_val}"));
-                std::process::exit(1);
-            });
-            let result = apply_algebra_to_tree_node(&tree.root_node, &x, &trees, use_math_tricks);
-            output(result.map_or("Undefined".to_string(), trim2));
-        } else {
-            output(format!("Function {func_name} not defined"));
-        }
-    } else {
-        output(format!("Invalid function call: {}", input.last().unwrap()));
-    }
+    cli::main(read_args());
 }
 
 /// Calculate the result of a binary tree.
@@ -166,131 +926,396 @@ pub fn apply_algebra_to_tree_node(
     x: &Dec,
     tablets: &Vec<BinaryAlgebraicExpressionTree>,
     use_math_tricks: bool,
-) -> Option<Dec> {
+) -> Value {
     match node {
-        TreeNode::Num(n) => Some(n.parse::<Dec>().unwrap()),
+        TreeNode::Num(n) => Value::Number(n.parse::<Dec>().unwrap()),
         TreeNode::Var(s) => {
             if s == "x" {
-                Some(x.clone())
+                Value::Number(x.clone())
             } else {
-                Some(
+                Value::Number(
                     s.parse::<Dec>()
                         .unwrap_or_else(|_| panic!("Unexpected variable: {s}")),
                 )
             }
         }
         TreeNode::Fun(name, iterate, arg) => {
-            let mut arg_value = apply_algebra_to_tree_node(arg, x, tablets, use_math_tricks);
-            let mut old_value = arg_value.clone();
-            for _ in 0..*iterate {
-                if name.as_str() == "abs" && use_math_tricks {
-                    arg_value = Some(math_trick::abs(arg_value.unwrap()).parse().unwrap());
-                } else if name.as_str() == "ge0" && use_math_tricks {
-                    arg_value = Some(math_trick::ge0(arg_value.unwrap()).parse().unwrap());
-                } else if name.as_str() == "is0" && use_math_tricks {
-                    arg_value = Some(math_trick::is0(arg_value.unwrap()).parse().unwrap());
-                } else if name.as_str() == "floor1" && use_math_tricks {
-                    arg_value = Some(math_trick::floor1(arg_value.unwrap()).parse().unwrap());
-                } else if name.as_str() == "left" && use_math_tricks {
-                    arg_value = Some(math_trick::left(arg_value.unwrap()).parse().unwrap());
-                } else {
-                    let tablet = tablets
-                        .iter()
-                        .find(|tablet| name == &tablet.name)
-                        .unwrap_or_else(|| panic!("There is no tree called {name}"));
-                    arg_value = apply_algebra_to_tree_node(
-                        &tablet.root_node,
-                        &arg_value.unwrap(),
-                        tablets,
-                        use_math_tricks,
-                    );
-                }
-                if arg_value == old_value {
-                    break;
+            let arg_value = apply_algebra_to_tree_node(arg, x, tablets, use_math_tricks);
+            apply_function(name, *iterate, arg_value, tablets, use_math_tricks)
+        }
+        TreeNode::Iterate(name, count, arg) => {
+            match iteration_count(name, count, tablets, use_math_tricks) {
+                Ok(iterate) => {
+                    let arg_value = apply_algebra_to_tree_node(arg, x, tablets, use_math_tricks);
+                    apply_function(name, iterate, arg_value, tablets, use_math_tricks)
                 }
-                old_value = arg_value.clone();
+                Err(reason) => Value::Undefined(reason),
             }
-            arg_value
         }
         TreeNode::Op(op, left, right) => {
             let left_val = apply_algebra_to_tree_node(left, x, tablets, use_math_tricks);
             let right_val = apply_algebra_to_tree_node(right, x, tablets, use_math_tricks);
-            let left_str = trim2(left_val.clone().unwrap());
-            let right_str = trim2(right_val.clone().unwrap());
-            match op {
-                '+' => Some(left_val.unwrap() + right_val.unwrap()),
-                '-' => Some(left_val.unwrap() - right_val.unwrap()),
-                '*' => Some(left_val.unwrap() * right_val.unwrap()),
+            left_val.combine(right_val, |left_val, right_val| match op {
+                '+' => Value::Number(left_val + right_val),
+                '-' => Value::Number(left_val - right_val),
+                '*' => Value::Number(left_val * right_val),
                 '/' => {
-                    if right_str == "0" {
-                        return None;
+                    if right_val == zero() {
+                        return Value::Undefined("division by zero".to_string());
                     }
-                    Some(left_val.unwrap() / right_val.unwrap())
+                    Value::Number(left_val / right_val)
                 }
                 '^' => {
-                    if (left_str == "0" && (right_val <= Some(zero())))
-                        || (left_val < Some(zero()) && right_str.contains('.'))
-                    {
-                        return None;
+                    if left_val == zero() && right_val <= zero() {
+                        return Value::Undefined("0 to a non-positive power".to_string());
+                    }
+                    if left_val < zero() && trim2(right_val.clone()).contains('.') {
+                        return Value::Undefined(
+                            "negative number to a non-integer power".to_string(),
+                        );
+                    }
+                    match pow(left_val, right_val) {
+                        Some(n) => Value::Number(n),
+                        None => Value::Undefined("exponent too large".to_string()),
                     }
-                    Some(pow(left_val.unwrap(), right_val.unwrap()))
                 }
                 _ => panic!("Unknown operator: {op}"),
-            }
+            })
         }
         TreeNode::Paren(expr) => apply_algebra_to_tree_node(expr, x, tablets, use_math_tricks),
-        TreeNode::Empty => Some(zero()),
+        TreeNode::Piecewise(..) => {
+            apply_algebra_to_tree_node(&lower_piecewise(node), x, tablets, use_math_tricks)
+        }
+        TreeNode::Series(..) => {
+            apply_algebra_to_tree_node(&expand_series(node), x, tablets, use_math_tricks)
+        }
+        TreeNode::Empty => Value::Number(zero()),
+    }
+}
+
+/// Applies the function `name` `iterate` times to `arg_value`.
+fn apply_function(
+    name: &str,
+    iterate: usize,
+    mut arg_value: Value,
+    tablets: &Vec<BinaryAlgebraicExpressionTree>,
+    use_math_tricks: bool,
+) -> Value {
+    let mut old_value = arg_value.clone();
+    for _ in 0..iterate {
+        // Undefined and Indeterminate are fixed points of every function.
+        let Value::Number(n) = &arg_value else {
+            break;
+        };
+        let n = n.clone();
+        arg_value = match name {
+            "abs" if use_math_tricks => math_trick::abs(n),
+            "ge0" if use_math_tricks => math_trick::ge0(n),
+            "is0" if use_math_tricks => math_trick::is0(n),
+            "floor1" if use_math_tricks => math_trick::floor1(n),
+            "left" if use_math_tricks => math_trick::left(n),
+            _ => {
+                let tablet = tablets
+                    .iter()
+                    .find(|tablet| name == tablet.name)
+                    .unwrap_or_else(|| panic!("There is no tree called {name}"));
+                match apply_algebra_to_tree_node(&tablet.root_node, &n, tablets, use_math_tricks) {
+                    // Only the innermost function call gets to name the location.
+                    Value::Undefined(reason) if !reason.contains(" at x=") => {
+                        Value::Undefined(format!("{reason} at x={} in {name}", trim2(n)))
+                    }
+                    value => value,
+                }
+            }
+        };
+        if arg_value == old_value {
+            break;
+        }
+        old_value = arg_value.clone();
+    }
+    arg_value
+}
+
+/// Evaluates the count of `name^[count](...)`, which must be a non-negative integer.
+fn iteration_count(
+    name: &str,
+    count: &TreeNode,
+    tablets: &Vec<BinaryAlgebraicExpressionTree>,
+    use_math_tricks: bool,
+) -> Result<usize, String> {
+    let expr = create_expression(count.clone());
+    match apply_algebra_to_tree_node(count, &zero(), tablets, use_math_tricks) {
+        Value::Number(n) => {
+            let n = trim2(n);
+            n.parse().map_err(|_| {
+                format!("Iteration count {expr} of {name} is {n}, expected a non-negative integer")
+            })
+        }
+        value => Err(format!("Iteration count {expr} of {name} is {value}")),
     }
 }
 
 /// Converts a String like 3*x+5 to a binary tree.
+/// Errors are skipped, use try_parse_expression to get them.
+///
+/// The grammar, whitespace is ignored:
+///
+/// ```text
+/// additive       = multiplicative { ("+" | "-") multiplicative }
+/// multiplicative = implicit { ("*" | "/") implicit }
+/// implicit       = unary { power }
+/// unary          = "+" unary | "-" number | "-" unary | power
+/// power          = atomic [ "^" unary ]
+/// atomic         = number | "x" | "(" additive ")" | name [ "^[" additive "]" ] "(" additive ")"
+///                | "√" atomic | "{" { additive "if" condition ";" } additive "otherwise" "}"
+///                | ( "sum" | "product" ) "(" name "=" integer ".." integer "," additive ")"
+///                | function { ( "∘" | "." ) function } [ "(" additive ")" ]
+///                | "(" function { ( "∘" | "." ) function } ")" "(" additive ")"
+/// function       = name [ "^[" additive "]" ]
+/// condition      = additive ( "<" | "<=" | ">" | ">=" | "==" ) additive
+/// number         = ( digits [ "." { digit } ] | "." digits ) [ ("e" | "E") [ "+" | "-" ] digits ]
+/// ```
+///
+/// As in school math, `^` is right-associative and binds tighter than a unary minus, so `2^3^2`
+/// is `2^(3^2)` and `-2^2` is `-(2^2)`. The other binary operators are left-associative. `-5` is
+/// the number `Num("-5")` unless it is the base of a power, any other unary minus `-e` becomes
+/// `0-e`. A parenthesised group always becomes a `Paren`. Factors next to each other like `2x`,
+/// `3(x+1)`, `x(x-1)` or `2abs(x)` are multiplied before `*` and `/`, so `1/2x` is `1/(2*x)`.
+///
+/// The tokenizer reads `×` and `·` as `*`, `÷` as `/`, `−` as `-` and superscripts like `x²` or
+/// `x⁻¹` as `x^2` and `x^-1`. `√e` is `e^(1/2)`.
+///
+/// `{ x^2 if x < 0; x otherwise }` becomes a `Piecewise`, `≤` and `≥` may replace `<=` and `>=`.
+/// `sum(k=1..9, k*is0(x-k))` becomes a `Series` of at most MAX_SERIES_TERMS terms.
+/// Compositions become nested calls: `(f ∘ g)(x+1)` is `f(g(x+1))` and `f ∘ g` without an
+/// argument is `f(g(x))`.
+///
+/// The iteration count in `^[...]` is a non-negative integer or a constant expression like
+/// `decimals(1)-1`, which becomes an `Iterate` node.
+///
+/// Digits may be separated by `_` like in `1_000_000`. Numbers are kept exactly in `Num` in the
+/// canonical form of parse_digits, e.g. `.5` becomes `0.5` and `2.5E+03` becomes `2.5e3`.
 pub fn parse_expression(s: &str) -> TreeNode {
-    let tokens: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
-    let mut index = 0;
-    parse_additive(&tokens, &mut index)
+    parse_expression_with_errors(s).0
+}
+
+/// Like parse_expression, but fails on anything that is not exactly one expression.
+pub fn try_parse_expression(s: &str) -> Result<TreeNode, Vec<ParseError>> {
+    let (node, errors) = parse_expression_with_errors(s);
+    if errors.is_empty() {
+        return Ok(node);
+    }
+    Err(errors
+        .into_iter()
+        .map(|(offset, message)| ParseError {
+            position: Position {
+                line: 1,
+                column: offset + 1,
+            },
+            message,
+        })
+        .collect())
+}
+
+/// Returns the errors as char offsets into `s`.
+fn parse_expression_with_errors(s: &str) -> (TreeNode, Vec<(usize, String)>) {
+    let mut p = Parser::new(s);
+    let node = parse_additive(&mut p);
+    if p.index < p.tokens.len() {
+        p.error(format!("Unexpected character {}", p.tokens[p.index]));
+    }
+    (node, p.errors)
 }
 
 /// Converts a binary tree back to a String like 3*x+5.
+///
+/// For every tree `t` that parse_expression returns, `parse_expression(&create_expression(t))`
+/// is `t` again, except that implicit multiplications are printed with `*` and parentheses.
+/// Other trees get the parentheses the grammar needs, so they are only stable from the second
+/// round trip on.
 pub fn create_expression(node: TreeNode) -> String {
-    fn build_expr(node: TreeNode, parent_prec: u8, is_root: bool) -> String {
+    create_expression_styled(node, ExpressionStyle::default())
+}
+
+/// Converts a binary tree back to a String like 3*x + 5, depending on the style.
+pub fn create_expression_styled(node: TreeNode, style: ExpressionStyle) -> String {
+    /// Exponents that can be written as superscripts, like `2` or `-1`.
+    fn is_integer(n: &str) -> bool {
+        let digits = n.strip_prefix('-').unwrap_or(n);
+        !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+    }
+    /// Operands of a unary minus that parse_unary reads without parentheses.
+    fn is_unary_operand(node: &TreeNode) -> bool {
         match node {
-            TreeNode::Op(op, left, right) => {
-                let (prec, is_left_assoc) = match op {
-                    '^' => (4, false),
-                    '*' | '/' => (3, true),
-                    '+' | '-' => (2, true),
-                    _ => (0, true),
-                };
-                let left_str = build_expr(*left, prec, false);
-                let right_str = build_expr(*right, prec + !is_left_assoc as u8, false);
-                let expr = format!("{left_str}{op}{right_str}");
-                if prec < parent_prec && !is_root {
+            TreeNode::Var(_)
+            | TreeNode::Fun(..)
+            | TreeNode::Iterate(..)
+            | TreeNode::Piecewise(..)
+            | TreeNode::Series(..)
+            | TreeNode::Paren(_) => true,
+            TreeNode::Op('^', ..) => true,
+            TreeNode::Num(n) => n.starts_with('-'),
+            TreeNode::Op('-', zero, operand) => {
+                **zero == TreeNode::Num("0".to_string()) && is_unary_operand(operand)
+            }
+            _ => false,
+        }
+    }
+    // Precedences: + and - 2, * and / 3, unary minus 4, ^ 5, everything else binds tightest.
+    fn build_expr(
+        node: TreeNode,
+        parent_prec: u8,
+        is_root: bool,
+        style: ExpressionStyle,
+    ) -> String {
+        match node {
+            TreeNode::Op('-', zero, operand)
+                if *zero == TreeNode::Num("0".to_string()) && is_unary_operand(&operand) =>
+            {
+                let minus = if style.unicode { '−' } else { '-' };
+                let expr = format!("{minus}{}", build_expr(*operand, 4, false, style));
+                if 4 < parent_prec && !is_root {
                     format!("({expr})")
                 } else {
                     expr
                 }
             }
-            TreeNode::Num(n) => n.to_string(),
-            TreeNode::Var(v) => v,
-            TreeNode::Fun(name, iterate, arg) => {
-                let mut iterating = "".to_string();
-                if iterate > 1 {
-                    iterating = "^[".to_string() + &iterate.to_string() + "]";
-                }
-                format!("{}{}({})", name, iterating, build_expr(*arg, 0, false))
+            TreeNode::Op('^', base, exponent)
+                if style.unicode && *exponent == square_root_exponent() =>
+            {
+                format!("√{}", build_expr(*base, 6, false, style))
             }
-            TreeNode::Paren(expr) => {
-                let inner = build_expr(*expr, 0, true);
-                if parent_prec > 0 {
-                    format!("({inner})")
+            TreeNode::Op(op, left, right) => {
+                let prec = match op {
+                    '^' => 5,
+                    '*' | '/' => 3,
+                    '+' | '-' => 2,
+                    _ => 0,
+                };
+                // The base of a power is atomic, the exponent may start with a unary minus.
+                let (left_prec, right_prec) = match op {
+                    '^' => (6, 4),
+                    _ => (prec, prec + 1),
+                };
+                let left_str = build_expr(*left, left_prec, false, style);
+                let superscript = match &*right {
+                    TreeNode::Num(n) if style.unicode && op == '^' && is_integer(n) => {
+                        Some(n.chars().map(ascii_to_superscript).collect::<String>())
+                    }
+                    _ => None,
+                };
+                let right_str = build_expr(*right, right_prec, false, style);
+                let op = match op {
+                    '*' if style.unicode => '·',
+                    '/' if style.unicode => '÷',
+                    '-' if style.unicode => '−',
+                    op => op,
+                };
+                let expr = if let Some(superscript) = superscript {
+                    format!("{left_str}{superscript}")
+                } else if style.spaces && (op == '+' || op == '-' || op == '−') {
+                    format!("{left_str} {op} {right_str}")
+                } else {
+                    format!("{left_str}{op}{right_str}")
+                };
+                if prec < parent_prec && !is_root {
+                    format!("({expr})")
                 } else {
-                    inner
+                    expr
+                }
+            }
+            TreeNode::Num(n) if style.unicode && n.starts_with('-') => {
+                let n = n.replacen('-', "−", 1);
+                if parent_prec > 5 { format!("({n})") } else { n }
+            }
+            TreeNode::Num(n) if n.starts_with('-') && parent_prec > 5 => format!("({n})"),
+            TreeNode::Num(n) => n.to_string(),
+            TreeNode::Var(v) => v,
+            TreeNode::Fun(_, _, ref arg) | TreeNode::Iterate(_, _, ref arg)
+                if style.composition
+                    && matches!(**arg, TreeNode::Fun(..) | TreeNode::Iterate(..)) =>
+            {
+                let mut functions = Vec::new();
+                let mut node = node;
+                let arg = loop {
+                    node = match node {
+                        TreeNode::Fun(name, 1, arg) => {
+                            functions.push(name);
+                            *arg
+                        }
+                        TreeNode::Fun(name, iterate, arg) => {
+                            functions.push(format!("{name}^[{iterate}]"));
+                            *arg
+                        }
+                        TreeNode::Iterate(name, count, arg) => {
+                            let count = build_expr(*count, 0, true, style);
+                            functions.push(format!("{name}^[{count}]"));
+                            *arg
+                        }
+                        other => break other,
+                    }
+                };
+                let operator = match (style.unicode, style.spaces) {
+                    (true, true) => " ∘ ",
+                    (true, false) => "∘",
+                    (false, true) => " . ",
+                    (false, false) => ".",
+                };
+                let functions = functions.join(operator);
+                match arg {
+                    TreeNode::Var(_) if is_root => functions,
+                    arg => format!("({functions})({})", build_expr(arg, 0, false, style)),
+                }
+            }
+            TreeNode::Fun(name, iterate, arg) => {
+                let mut iterating = "".to_string();
+                if iterate != 1 {
+                    iterating = "^[".to_string() + &iterate.to_string() + "]";
                 }
+                format!(
+                    "{}{}({})",
+                    name,
+                    iterating,
+                    build_expr(*arg, 0, false, style)
+                )
             }
+            TreeNode::Iterate(name, count, arg) => format!(
+                "{name}^[{}]({})",
+                build_expr(*count, 0, true, style),
+                build_expr(*arg, 0, false, style)
+            ),
+            TreeNode::Piecewise(cases, otherwise) => {
+                let mut parts: Vec<String> = cases
+                    .into_iter()
+                    .map(|case| {
+                        format!(
+                            "{} if {} {} {}",
+                            build_expr(case.value, 0, true, style),
+                            build_expr(case.left, 0, true, style),
+                            case.comparison,
+                            build_expr(case.right, 0, true, style)
+                        )
+                    })
+                    .collect();
+                parts.push(format!(
+                    "{} otherwise",
+                    build_expr(*otherwise, 0, true, style)
+                ));
+                format!("{{ {} }}", parts.join("; "))
+            }
+            TreeNode::Series(op, index, from, to, term) => {
+                let term = replace_constant(&term, &index, &TreeNode::Var(index.clone()));
+                format!(
+                    "{}({index}={from}..{to}, {})",
+                    if op == '*' { "product" } else { "sum" },
+                    build_expr(term, 0, true, style)
+                )
+            }
+            TreeNode::Paren(expr) => format!("({})", build_expr(*expr, 0, true, style)),
             TreeNode::Empty => String::new(),
         }
     }
-    build_expr(node, 0, true)
+    build_expr(node, 0, true, style)
 }
 
 pub fn level_order_to_array(root: TreeNode) -> [String; 15] {
@@ -309,7 +1334,7 @@ pub fn level_order_to_array(root: TreeNode) -> [String; 15] {
             }
             TreeNode::Num(n) => result[i] = n.to_string(),
             TreeNode::Var(v) => result[i] = v,
-            TreeNode::Fun(name, _iterate, arg) => {
+            TreeNode::Fun(name, _, arg) | TreeNode::Iterate(name, _, arg) => {
                 result[i] = name;
                 queue.push_back((2 * i + 2, *arg));
             }
@@ -317,6 +1342,8 @@ pub fn level_order_to_array(root: TreeNode) -> [String; 15] {
                 result[i] = "()".to_string();
                 queue.push_back((2 * i + 1, *expr));
             }
+            TreeNode::Piecewise(..) => queue.push_front((i, lower_piecewise(&node))),
+            TreeNode::Series(..) => queue.push_front((i, expand_series(&node))),
             TreeNode::Empty => {}
         }
     }
@@ -328,7 +1355,7 @@ fn trim2(dec: Dec) -> String {
     if x.contains('.') {
         x = x.trim_end_matches('0').trim_end_matches('.').to_string();
     }
-    if x.starts_with("-0") {
+    if x == "-0" {
         x.remove(0);
     }
     x
@@ -338,14 +1365,17 @@ fn parse_function(s: &str) -> Option<(&str, &str)> {
     let s = s.trim();
     if let Some((func_part, expr)) = s.split_once('=') {
         let func_part = func_part.trim();
-        if let Some((name, _)) = func_part.split_once('(') {
-            return Some((name, expr.trim()));
-        }
+        // `name = expression` defines a constant.
+        let name = func_part
+            .split_once('(')
+            .map_or(func_part, |(name, _)| name);
+        return Some((name, expr.trim()));
     }
     None
 }
 
 fn parse_function_call(s: &str) -> Option<(&str, &str)> {
+    let s = s.trim();
     if !s.ends_with(')') || !s.contains('(') {
         return None;
     }
@@ -355,6 +1385,322 @@ fn parse_function_call(s: &str) -> Option<(&str, &str)> {
     Some((name, input))
 }
 
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn parse_definition(line: &SourceLine) -> Result<BinaryAlgebraicExpressionTree, Vec<ParseError>> {
+    let start = line.text.len() - line.text.trim_start().len();
+    let (name, expr) = match parse_function(&line.text) {
+        Some((name, expr)) if is_identifier(name.trim()) && name.trim() != "x" => {
+            (name.trim(), expr)
+        }
+        _ => {
+            return Err(vec![line.error(
+                start,
+                "Invalid function definition, expected name(x) = expression".to_string(),
+            )]);
+        }
+    };
+    let param = line.text[..line.text.find('=').unwrap()].trim();
+    if !matches!(
+        param
+            .strip_prefix(name)
+            .map(|p| p.replace(' ', ""))
+            .as_deref(),
+        Some("(x)" | "")
+    ) {
+        return Err(vec![line.error(
+            start,
+            format!("Invalid function definition, expected {name}(x) = expression"),
+        )]);
+    }
+    Ok(BinaryAlgebraicExpressionTree {
+        name: name.to_string(),
+        root_node: line.parse_part(expr)?,
+    })
+}
+
+fn parse_query(line: &SourceLine) -> Result<Query, Vec<ParseError>> {
+    let Some((name, input)) = parse_function_call(&line.text) else {
+        let start = line.text.len() - line.text.trim_start().len();
+        return Err(vec![line.error(
+            start,
+            format!("Invalid function call: {}", line.text.trim()),
+        )]);
+    };
+    let name = name.trim().to_string();
+    if let Some((start, end, step)) = split_range(input) {
+        let step = match step {
+            Some(step) => line.parse_part(step),
+            None => Ok(TreeNode::Num("1".to_string())),
+        };
+        let (start, end) = (line.parse_part(start), line.parse_part(end));
+        return match (start, end, step) {
+            (Ok(start), Ok(end), Ok(step)) => Ok(Query::Range {
+                name,
+                start,
+                end,
+                step,
+            }),
+            (start, end, step) => Err([start.err(), end.err(), step.err()]
+                .into_iter()
+                .flatten()
+                .flatten()
+                .collect()),
+        };
+    }
+    Ok(Query::Call {
+        name,
+        input: line.parse_part(input)?,
+    })
+}
+
+/// Splits the input of a range query like `0..1 step 1/4` into start, end and the optional step.
+/// `..` and `step` count only outside of brackets, and `step` only as a whole word after the
+/// end, so `0..step(1)` calls a function step.
+fn split_range(input: &str) -> Option<(&str, &str, Option<&str>)> {
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+    let mut depth = 0;
+    let mut dots = None;
+    for (i, c) in input.char_indices() {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            _ if depth != 0 => {}
+            '.' if dots.is_none() && input[i..].starts_with("..") => dots = Some(i),
+            's' if input[i..].starts_with("step") => {
+                let Some(dots) = dots else { continue };
+                let end = input[dots + 2..i].trim_end();
+                let after_end = end
+                    .chars()
+                    .last()
+                    .is_some_and(|c| is_word_char(c) || matches!(c, ')' | ']' | '}' | '.'));
+                let whole_word = !input[..i].ends_with(is_word_char)
+                    && !input[i + 4..].starts_with(is_word_char);
+                if after_end && whole_word {
+                    return Some((&input[..dots], &input[dots + 2..i], Some(&input[i + 4..])));
+                }
+            }
+            _ => {}
+        }
+    }
+    dots.map(|dots| (&input[..dots], &input[dots + 2..], None))
+}
+
+/// Replaces every x in `node` by `value`.
+fn replace_x(node: &TreeNode, value: &TreeNode) -> TreeNode {
+    match node {
+        TreeNode::Var(_) => value.clone(),
+        TreeNode::Op(op, left, right) => TreeNode::Op(
+            *op,
+            Box::new(replace_x(left, value)),
+            Box::new(replace_x(right, value)),
+        ),
+        TreeNode::Fun(name, iterate, arg) => {
+            TreeNode::Fun(name.clone(), *iterate, Box::new(replace_x(arg, value)))
+        }
+        TreeNode::Iterate(name, count, arg) => {
+            TreeNode::Iterate(name.clone(), count.clone(), Box::new(replace_x(arg, value)))
+        }
+        TreeNode::Paren(expr) => TreeNode::Paren(Box::new(replace_x(expr, value))),
+        TreeNode::Piecewise(..) => replace_x(&lower_piecewise(node), value),
+        TreeNode::Series(..) => replace_x(&expand_series(node), value),
+        TreeNode::Num(_) | TreeNode::Empty => node.clone(),
+    }
+}
+
+/// Replaces every piecewise expression in `node` by the products that compute it with ge0, lt1
+/// and is0. `{ a if x < 1; b otherwise }` becomes `lt1(x)*a + (1 - lt1(x))*b`. A case counts
+/// if its condition holds and the conditions of all cases before it do not.
+pub fn lower_piecewise(node: &TreeNode) -> TreeNode {
+    let lower = |node: &TreeNode| Box::new(lower_piecewise(node));
+    match node {
+        TreeNode::Piecewise(cases, otherwise) => {
+            let num = |n: &str| Box::new(TreeNode::Num(n.to_string()));
+            let call =
+                |name: &str, arg: TreeNode| TreeNode::Fun(name.to_string(), 1, Box::new(arg));
+            let minus = |left: &TreeNode, right: &TreeNode| match right {
+                TreeNode::Num(n) if n == "0" => lower_piecewise(left),
+                _ => TreeNode::Op('-', lower(left), lower(right)),
+            };
+            // `a < b` is lt1(a - b + 1), which is lt1(a) for b = 1.
+            let less = |left: &TreeNode, right: &TreeNode| match right {
+                TreeNode::Num(n) if n == "1" => call("lt1", lower_piecewise(left)),
+                _ => call(
+                    "lt1",
+                    TreeNode::Op('+', Box::new(minus(left, right)), num("1")),
+                ),
+            };
+            let mut terms = Vec::new();
+            let mut earlier: Vec<TreeNode> = Vec::new();
+            for case in cases {
+                let condition = match case.comparison.as_str() {
+                    "<" => less(&case.left, &case.right),
+                    ">" => less(&case.right, &case.left),
+                    "<=" => call("ge0", minus(&case.right, &case.left)),
+                    ">=" => call("ge0", minus(&case.left, &case.right)),
+                    _ => call("is0", minus(&case.left, &case.right)),
+                };
+                terms.push((earlier.clone(), Some(condition.clone()), &case.value));
+                earlier.push(TreeNode::Op('-', num("1"), Box::new(condition)));
+            }
+            terms.push((earlier, None, &**otherwise));
+            terms
+                .into_iter()
+                .filter(|(_, _, value)| **value != TreeNode::Num("0".to_string()))
+                .map(|(earlier, condition, value)| {
+                    earlier
+                        .into_iter()
+                        .chain(condition)
+                        .chain(
+                            (*value != TreeNode::Num("1".to_string()))
+                                .then(|| lower_piecewise(value)),
+                        )
+                        .reduce(|product, factor| {
+                            TreeNode::Op('*', Box::new(product), Box::new(factor))
+                        })
+                        .unwrap_or(TreeNode::Num("1".to_string()))
+                })
+                .reduce(|sum, term| TreeNode::Op('+', Box::new(sum), Box::new(term)))
+                .unwrap_or(TreeNode::Num("0".to_string()))
+        }
+        TreeNode::Op(op, left, right) => TreeNode::Op(*op, lower(left), lower(right)),
+        TreeNode::Fun(name, iterate, arg) => TreeNode::Fun(name.clone(), *iterate, lower(arg)),
+        TreeNode::Iterate(name, count, arg) => {
+            TreeNode::Iterate(name.clone(), lower(count), lower(arg))
+        }
+        TreeNode::Paren(expr) => TreeNode::Paren(lower(expr)),
+        TreeNode::Series(op, index, from, to, term) => {
+            TreeNode::Series(*op, index.clone(), *from, *to, lower(term))
+        }
+        TreeNode::Num(_) | TreeNode::Var(_) | TreeNode::Empty => node.clone(),
+    }
+}
+
+/// Replaces every `sum` and `product` in `node` by its terms, so `sum(k=1..3, k*x)` becomes
+/// `1*x+2*x+3*x`. The index is a constant in the term, every `k` is replaced by its value. An
+/// empty sum is 0 and an empty product 1.
+pub fn expand_series(node: &TreeNode) -> TreeNode {
+    let expand = |node: &TreeNode| Box::new(expand_series(node));
+    match node {
+        TreeNode::Series(op, index, from, to, term) => {
+            let term = expand_series(term);
+            (*from..=*to)
+                .map(|k| replace_constant(&term, index, &TreeNode::Num(k.to_string())))
+                .reduce(|left, right| TreeNode::Op(*op, Box::new(left), Box::new(right)))
+                .unwrap_or(TreeNode::Num(
+                    if *op == '*' { "1" } else { "0" }.to_string(),
+                ))
+        }
+        TreeNode::Op(op, left, right) => TreeNode::Op(*op, expand(left), expand(right)),
+        TreeNode::Fun(name, iterate, arg) => TreeNode::Fun(name.clone(), *iterate, expand(arg)),
+        TreeNode::Iterate(name, count, arg) => {
+            TreeNode::Iterate(name.clone(), expand(count), expand(arg))
+        }
+        TreeNode::Piecewise(cases, otherwise) => TreeNode::Piecewise(
+            cases
+                .iter()
+                .map(|case| Case {
+                    value: expand_series(&case.value),
+                    left: expand_series(&case.left),
+                    comparison: case.comparison.clone(),
+                    right: expand_series(&case.right),
+                })
+                .collect(),
+            expand(otherwise),
+        ),
+        TreeNode::Paren(expr) => TreeNode::Paren(expand(expr)),
+        TreeNode::Num(_) | TreeNode::Var(_) | TreeNode::Empty => node.clone(),
+    }
+}
+
+/// Replaces every constant `name` in `node` by `value`, except in a series with the index `name`.
+fn replace_constant(node: &TreeNode, name: &str, value: &TreeNode) -> TreeNode {
+    let replace = |node: &TreeNode| Box::new(replace_constant(node, name, value));
+    match node {
+        TreeNode::Fun(n, 1, arg) if n == name && **arg == TreeNode::Num("0".to_string()) => {
+            value.clone()
+        }
+        TreeNode::Op(op, left, right) => TreeNode::Op(*op, replace(left), replace(right)),
+        TreeNode::Fun(n, iterate, arg) => TreeNode::Fun(n.clone(), *iterate, replace(arg)),
+        TreeNode::Iterate(n, count, arg) => {
+            TreeNode::Iterate(n.clone(), replace(count), replace(arg))
+        }
+        TreeNode::Piecewise(cases, otherwise) => TreeNode::Piecewise(
+            cases
+                .iter()
+                .map(|case| Case {
+                    value: replace_constant(&case.value, name, value),
+                    left: replace_constant(&case.left, name, value),
+                    comparison: case.comparison.clone(),
+                    right: replace_constant(&case.right, name, value),
+                })
+                .collect(),
+            replace(otherwise),
+        ),
+        TreeNode::Series(op, index, from, to, term) if index != name => {
+            TreeNode::Series(*op, index.clone(), *from, *to, replace(term))
+        }
+        TreeNode::Paren(expr) => TreeNode::Paren(replace(expr)),
+        TreeNode::Num(_) | TreeNode::Var(_) | TreeNode::Series(..) | TreeNode::Empty => {
+            node.clone()
+        }
+    }
+}
+
+fn count_nodes(node: &TreeNode) -> usize {
+    match node {
+        TreeNode::Op(_, left, right) => 1 + count_nodes(left) + count_nodes(right),
+        TreeNode::Fun(_, _, arg) | TreeNode::Paren(arg) => 1 + count_nodes(arg),
+        TreeNode::Iterate(_, count, arg) => 1 + count_nodes(count) + count_nodes(arg),
+        TreeNode::Piecewise(..) => count_nodes(&lower_piecewise(node)),
+        TreeNode::Series(..) => count_nodes(&expand_series(node)),
+        TreeNode::Num(_) | TreeNode::Var(_) | TreeNode::Empty => 1,
+    }
+}
+
+/// Collects the names of all functions called in `node`.
+fn function_names(node: &TreeNode, names: &mut Vec<String>) {
+    match node {
+        TreeNode::Fun(name, _, arg) => {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+            function_names(arg, names);
+        }
+        TreeNode::Iterate(name, count, arg) => {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+            function_names(count, names);
+            function_names(arg, names);
+        }
+        TreeNode::Op(_, left, right) => {
+            function_names(left, names);
+            function_names(right, names);
+        }
+        TreeNode::Paren(expr) => function_names(expr, names),
+        TreeNode::Piecewise(..) => function_names(&lower_piecewise(node), names),
+        TreeNode::Series(..) => function_names(&expand_series(node), names),
+        TreeNode::Num(_) | TreeNode::Var(_) | TreeNode::Empty => {}
+    }
+}
+
+fn contains_node(node: &TreeNode, predicate: &dyn Fn(&TreeNode) -> bool) -> bool {
+    predicate(node)
+        || match node {
+            TreeNode::Fun(_, _, arg) | TreeNode::Paren(arg) => contains_node(arg, predicate),
+            TreeNode::Op(_, left, right) | TreeNode::Iterate(_, left, right) => {
+                contains_node(left, predicate) || contains_node(right, predicate)
+            }
+            TreeNode::Piecewise(..) => contains_node(&lower_piecewise(node), predicate),
+            TreeNode::Series(..) => contains_node(&expand_series(node), predicate),
+            TreeNode::Num(_) | TreeNode::Var(_) | TreeNode::Empty => false,
+        }
+}
+
 fn trim_zeros(s: &str) -> String {
     if s.contains('.') {
         let trimmed = s.trim_end_matches('0');
@@ -364,14 +1710,101 @@ fn trim_zeros(s: &str) -> String {
     }
 }
 
-fn parse_additive(tokens: &[char], index: &mut usize) -> TreeNode {
-    let mut left = parse_multiplicative(tokens, index);
-    while *index < tokens.len() {
-        match tokens[*index] {
+/// State of the recursive descent parser. Whitespace is skipped, but every token remembers its
+/// char offset in the parsed text so that errors can point into the source.
+struct Parser {
+    tokens: Vec<char>,
+    offsets: Vec<usize>,
+    len: usize,
+    index: usize,
+    errors: Vec<(usize, String)>,
+    warnings: Vec<(usize, String)>,
+    /// The offsets of the second factors of implicit multiplications.
+    implicit: Vec<usize>,
+    /// The offsets and names of constants written without an argument.
+    constants: Vec<(usize, String)>,
+    /// The tokens of the last composition without an argument like `f ∘ g`, which means `f(g(x))`.
+    composition: Option<(usize, usize)>,
+}
+
+impl Parser {
+    /// Skips whitespace and replaces the Unicode aliases `×`, `·`, `÷` and `−` by their ASCII
+    /// operators. A run of superscripts like `²` or `⁻¹` becomes `^` and the exponent.
+    fn new(s: &str) -> Self {
+        let mut tokens = Vec::new();
+        let mut offsets = Vec::new();
+        let mut in_superscript = false;
+        for (offset, c) in s.chars().enumerate().filter(|(_, c)| !c.is_whitespace()) {
+            let superscript = superscript_to_ascii(c);
+            if superscript.is_some() && !in_superscript {
+                tokens.push('^');
+                offsets.push(offset);
+            }
+            in_superscript = superscript.is_some();
+            tokens.push(superscript.unwrap_or(match c {
+                '×' | '·' => '*',
+                '÷' => '/',
+                '−' => '-',
+                c => c,
+            }));
+            offsets.push(offset);
+        }
+        Parser {
+            tokens,
+            offsets,
+            len: s.chars().count(),
+            index: 0,
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            implicit: Vec::new(),
+            constants: Vec::new(),
+            composition: None,
+        }
+    }
+
+    /// Records an error at the current token.
+    fn error(&mut self, message: String) {
+        let offset = self.offsets.get(self.index).copied().unwrap_or(self.len);
+        self.errors.push((offset, message));
+    }
+
+    /// Records that the expression at `offset` meant something else before ^ became
+    /// right-associative and started to bind tighter than a unary minus.
+    fn warning(&mut self, offset: usize, message: String) {
+        self.warnings.push((offset, message));
+    }
+}
+
+fn superscript_to_ascii(c: char) -> Option<char> {
+    match c {
+        '⁰' => Some('0'),
+        '¹' => Some('1'),
+        '²' => Some('2'),
+        '³' => Some('3'),
+        '⁴'..='⁹' => char::from_digit(c as u32 - '⁰' as u32, 10),
+        '⁻' => Some('-'),
+        _ => None,
+    }
+}
+
+fn ascii_to_superscript(c: char) -> char {
+    match c {
+        '1' => '¹',
+        '2' => '²',
+        '3' => '³',
+        '-' => '⁻',
+        c => char::from_u32('⁰' as u32 + c.to_digit(10).unwrap_or(0)).unwrap_or(c),
+    }
+}
+
+fn parse_additive(p: &mut Parser) -> TreeNode {
+    let mut left = parse_multiplicative(p);
+    while p.index < p.tokens.len() {
+        match p.tokens[p.index] {
             '+' | '-' => {
-                let op = tokens[*index];
-                *index += 1;
-                let right = parse_multiplicative(tokens, index);
+                let op = p.tokens[p.index];
+                p.index += 1;
+                let right = parse_multiplicative(p);
                 left = TreeNode::Op(op, Box::new(left), Box::new(right));
             }
             _ => break,
@@ -380,14 +1813,14 @@ fn parse_additive(tokens: &[char], index: &mut usize) -> TreeNode {
     left
 }
 
-fn parse_multiplicative(tokens: &[char], index: &mut usize) -> TreeNode {
-    let mut left = parse_power(tokens, index);
-    while *index < tokens.len() {
-        match tokens[*index] {
+fn parse_multiplicative(p: &mut Parser) -> TreeNode {
+    let mut left = parse_implicit(p);
+    while p.index < p.tokens.len() {
+        match p.tokens[p.index] {
             '*' | '/' => {
-                let op = tokens[*index];
-                *index += 1;
-                let right = parse_power(tokens, index);
+                let op = p.tokens[p.index];
+                p.index += 1;
+                let right = parse_implicit(p);
                 left = TreeNode::Op(op, Box::new(left), Box::new(right));
             }
             _ => break,
@@ -396,235 +1829,600 @@ fn parse_multiplicative(tokens: &[char], index: &mut usize) -> TreeNode {
     left
 }
 
-fn parse_atomic(tokens: &[char], index: &mut usize) -> TreeNode {
-    if *index >= tokens.len() {
+/// Factors written next to each other like `2x` or `(x+1)(x-1)` are multiplied. This binds
+/// tighter than `*` and `/`, so `1/2x` is `1/(2*x)`.
+fn parse_implicit(p: &mut Parser) -> TreeNode {
+    let mut left = parse_unary(p);
+    while p.index < p.tokens.len()
+        && (p.tokens[p.index].is_ascii_alphanumeric() || matches!(p.tokens[p.index], '(' | '√'))
+        && !at_keyword(p)
+    {
+        p.implicit.push(p.offsets[p.index]);
+        let right = parse_power(p);
+        left = TreeNode::Op('*', Box::new(left), Box::new(right));
+    }
+    left
+}
+
+/// Whether a number like `5` or `.5` starts at `index`.
+fn starts_number(p: &Parser, index: usize) -> bool {
+    match p.tokens.get(index) {
+        Some('.') => p.tokens.get(index + 1).is_some_and(|c| c.is_ascii_digit()),
+        Some(c) => c.is_ascii_digit(),
+        None => false,
+    }
+}
+
+/// Reads a number like `12`, `1_000.5`, `.5` or `2.5E+3` in its canonical form: without `_`
+/// separators, with a leading `0` and with a lowercase `e` and no `+` or leading zeros in the
+/// exponent. The digits are kept exactly.
+fn parse_digits(p: &mut Parser, num_str: &mut String) {
+    fn digits(p: &mut Parser, num_str: &mut String) {
+        while p.index < p.tokens.len() {
+            match p.tokens[p.index] {
+                c if c.is_ascii_digit() => num_str.push(c),
+                '_' if num_str.ends_with(|c: char| c.is_ascii_digit())
+                    && p.tokens
+                        .get(p.index + 1)
+                        .is_some_and(|c| c.is_ascii_digit()) => {}
+                _ => break,
+            }
+            p.index += 1;
+        }
+    }
+    if p.index < p.tokens.len() && p.tokens[p.index] == '.' {
+        num_str.push('0');
+    }
+    digits(p, num_str);
+    if p.index < p.tokens.len() && p.tokens[p.index] == '.' {
+        num_str.push(p.tokens[p.index]);
+        p.index += 1;
+        digits(p, num_str);
+    }
+    if p.index < p.tokens.len() && matches!(p.tokens[p.index], 'e' | 'E') {
+        let sign = p
+            .tokens
+            .get(p.index + 1)
+            .filter(|c| matches!(c, '+' | '-'))
+            .copied();
+        let start = p.index + 1 + sign.is_some() as usize;
+        if p.tokens.get(start).is_some_and(|c| c.is_ascii_digit()) {
+            p.index = start;
+            let mut exponent = String::new();
+            digits(p, &mut exponent);
+            let exponent = exponent.trim_start_matches('0');
+            num_str.push('e');
+            if sign == Some('-') && !exponent.is_empty() {
+                num_str.push('-');
+            }
+            num_str.push_str(if exponent.is_empty() { "0" } else { exponent });
+        }
+    }
+}
+
+/// Consumes the `)` that closes a group or a function call.
+fn expect_closing_paren(p: &mut Parser) {
+    if p.index < p.tokens.len() && p.tokens[p.index] == ')' {
+        p.index += 1;
+    } else {
+        p.error("Missing closing parenthesis".to_string());
+    }
+}
+
+fn parse_atomic(p: &mut Parser) -> TreeNode {
+    if p.index >= p.tokens.len() {
+        p.error("Expected an expression".to_string());
         return TreeNode::Empty;
     }
-    let c = tokens[*index];
+    let c = p.tokens[p.index];
 
     match c {
         '(' => {
-            *index += 1;
-            let node = parse_additive(tokens, index);
-            if *index < tokens.len() && tokens[*index] == ')' {
-                *index += 1;
+            p.index += 1;
+            let start = p.index;
+            let node = parse_additive(p);
+            let is_composition = p.composition == Some((start, p.index));
+            expect_closing_paren(p);
+            // (f ∘ g)(arg) applies the composition.
+            if is_composition && p.tokens.get(p.index) == Some(&'(') {
+                p.index += 1;
+                let arg = parse_additive(p);
+                expect_closing_paren(p);
+                return replace_x(&node, &arg);
             }
             TreeNode::Paren(Box::new(node))
         }
-        '0'..='9' => {
+        '0'..='9' | '.' if starts_number(p, p.index) => {
             let mut num_str = String::new();
-            while *index < tokens.len() && tokens[*index].is_ascii_digit() {
-                num_str.push(tokens[*index]);
-                *index += 1;
-            }
-            if *index < tokens.len() && tokens[*index] == '.' {
-                num_str.push(tokens[*index]);
-                *index += 1;
-                while *index < tokens.len() && tokens[*index].is_ascii_digit() {
-                    num_str.push(tokens[*index]);
-                    *index += 1;
-                }
-            }
+            parse_digits(p, &mut num_str);
             TreeNode::Num(num_str)
         }
+        '√' => {
+            p.index += 1;
+            let base = parse_atomic(p);
+            TreeNode::Op('^', Box::new(base), Box::new(square_root_exponent()))
+        }
+        '{' => {
+            p.index += 1;
+            parse_piecewise(p)
+        }
         'A'..='Z' | 'a'..='z' => {
-            let mut name = String::new();
-            let mut iterate: usize = 1;
-            while *index < tokens.len()
-                && (tokens[*index].is_alphanumeric() || tokens[*index] == '_')
-            {
-                name.push(tokens[*index]);
-                *index += 1;
-            }
-            if *index < tokens.len() && tokens[*index] == '^' {
-                let saved_index = *index;
-                *index += 1;
-                if *index < tokens.len() && tokens[*index] == '[' {
-                    *index += 1;
-                    let mut num_str = String::new();
-                    while *index < tokens.len() && tokens[*index].is_ascii_digit() {
-                        num_str.push(tokens[*index]);
-                        *index += 1;
-                    }
-                    if *index < tokens.len() && tokens[*index] == ']' {
-                        *index += 1;
-                        if let Ok(parsed_iterate) = num_str.parse::<usize>() {
-                            iterate = parsed_iterate;
-                        }
-                    } else {
-                        *index = saved_index;
-                    }
-                } else {
-                    *index = saved_index;
-                }
+            let start = p.index;
+            let name = parse_name(p);
+            if (name == "sum" || name == "product") && starts_series(p) {
+                return parse_series(p, if name == "sum" { '+' } else { '*' });
             }
-
-            if *index < tokens.len() && tokens[*index] == '(' {
-                *index += 1;
-                let arg = parse_additive(tokens, index);
-                if *index < tokens.len() && tokens[*index] == ')' {
-                    *index += 1;
-                }
-                TreeNode::Fun(name, iterate, Box::new(arg))
-            } else if name == "x" {
-                TreeNode::Var("x".to_string())
-            } else {
-                TreeNode::Empty
+            let (iterate, count) = parse_iteration(p);
+            let is_variable = name == "x" && iterate == 1 && count.is_none();
+            if !is_variable && at_composition(p) {
+                return parse_composition(p, start, (name, iterate, count));
             }
+            // x(x+1) is a product, x is no function name.
+            let arg = if p.index < p.tokens.len() && p.tokens[p.index] == '(' && !is_variable {
+                p.index += 1;
+                let arg = parse_additive(p);
+                expect_closing_paren(p);
+                arg
+            } else if is_variable {
+                return TreeNode::Var("x".to_string());
+            } else {
+                // A constant like `decimals` is the call decimals(0).
+                p.constants.push((p.offsets[start], name.clone()));
+                TreeNode::Num("0".to_string())
+            };
+            call(name, iterate, count, arg)
         }
         _ => {
-            *index += 1;
+            p.error(format!("Unexpected character {c}"));
+            p.index += 1;
             TreeNode::Empty
         }
     }
 }
 
-fn parse_unary(tokens: &[char], index: &mut usize) -> TreeNode {
-    if *index >= tokens.len() {
+/// Whether the word `if` or `otherwise` starts at the current token. Whitespace is skipped by
+/// the tokenizer, so the word must be separated from the tokens around it in the source.
+fn at_keyword(p: &Parser) -> bool {
+    let is_word_char = |i: usize| p.tokens[i].is_alphanumeric() || p.tokens[i] == '_';
+    let joined = |i: usize| p.offsets[i] + 1 == p.offsets[i + 1];
+    let i = p.index;
+    ["if", "otherwise"].iter().any(|keyword| {
+        let end = i + keyword.len();
+        p.tokens
+            .get(i..end)
+            .is_some_and(|t| t.iter().copied().eq(keyword.chars()))
+            && !(i > 0 && is_word_char(i - 1) && joined(i - 1))
+            && !(end < p.tokens.len() && is_word_char(end) && joined(end - 1))
+    })
+}
+
+/// Reads the cases of `{ a if x < 1; b otherwise }` after the `{`.
+fn parse_piecewise(p: &mut Parser) -> TreeNode {
+    let mut cases = Vec::new();
+    loop {
+        let value = parse_additive(p);
+        if at_keyword(p) && p.tokens[p.index] == 'o' {
+            p.index += "otherwise".len();
+            if p.tokens.get(p.index) == Some(&'}') {
+                p.index += 1;
+            } else {
+                p.error("Expected } after the otherwise case".to_string());
+            }
+            return TreeNode::Piecewise(cases, Box::new(value));
+        }
+        if !at_keyword(p) {
+            p.error("Expected if or otherwise after the value of a case".to_string());
+            return skip_piecewise(p);
+        }
+        p.index += "if".len();
+        let left = parse_additive(p);
+        let (comparison, width) = match (p.tokens.get(p.index), p.tokens.get(p.index + 1)) {
+            (Some(c @ ('<' | '>' | '=')), Some('=')) => (format!("{c}="), 2),
+            (Some(c @ ('<' | '>')), _) => (c.to_string(), 1),
+            (Some('≤'), _) => ("<=".to_string(), 1),
+            (Some('≥'), _) => (">=".to_string(), 1),
+            _ => {
+                p.error("Expected <, <=, >, >= or == in the condition".to_string());
+                return skip_piecewise(p);
+            }
+        };
+        p.index += width;
+        let right = parse_additive(p);
+        cases.push(Case {
+            value,
+            left,
+            comparison,
+            right,
+        });
+        if p.tokens.get(p.index) == Some(&';') {
+            p.index += 1;
+        } else {
+            p.error("Expected ; after the condition, the last case is otherwise".to_string());
+            return skip_piecewise(p);
+        }
+    }
+}
+
+/// Reads a function name like `floor1` or `is_0`.
+fn parse_name(p: &mut Parser) -> String {
+    let start = p.index;
+    let mut name = String::new();
+    while p.index < p.tokens.len()
+        && (p.tokens[p.index].is_alphanumeric() || p.tokens[p.index] == '_')
+        && !(p.index > start && at_keyword(p))
+    {
+        name.push(p.tokens[p.index]);
+        p.index += 1;
+    }
+    name
+}
+
+/// Reads an optional `^[count]` after a function name. A constant count that is no integer
+/// literal is returned as a tree.
+fn parse_iteration(p: &mut Parser) -> (usize, Option<TreeNode>) {
+    if p.index + 1 >= p.tokens.len() || p.tokens[p.index] != '^' || p.tokens[p.index + 1] != '[' {
+        return (1, None);
+    }
+    p.index += 2;
+    let count_start = p.index;
+    let node = parse_additive(p);
+    if p.index < p.tokens.len() && p.tokens[p.index] == ']' {
+        p.index += 1;
+    } else {
+        p.error("Expected ] after the iteration count".to_string());
+        while p.index < p.tokens.len() && p.tokens[p.index] != ']' {
+            p.index += 1;
+        }
+        p.index += 1;
+    }
+    match &node {
+        TreeNode::Num(n) if n.parse::<usize>().is_ok() => (n.parse().unwrap(), None),
+        _ if contains_node(&node, &|n| matches!(n, TreeNode::Var(_))) => {
+            let end = p.index;
+            p.index = count_start;
+            p.error("The iteration count must not depend on x".to_string());
+            p.index = end;
+            (1, None)
+        }
+        _ => (1, Some(node)),
+    }
+}
+
+/// The call `name^[iterate](arg)` or, with a count, `name^[count](arg)`.
+fn call(name: String, iterate: usize, count: Option<TreeNode>, arg: TreeNode) -> TreeNode {
+    match count {
+        Some(count) => TreeNode::Iterate(name, Box::new(count), Box::new(arg)),
+        None => TreeNode::Fun(name, iterate, Box::new(arg)),
+    }
+}
+
+/// Whether `∘` or `.` between two function names follows.
+fn at_composition(p: &Parser) -> bool {
+    match p.tokens.get(p.index) {
+        Some('∘') => true,
+        Some('.') => p
+            .tokens
+            .get(p.index + 1)
+            .is_some_and(char::is_ascii_alphabetic),
+        _ => false,
+    }
+}
+
+/// Reads the rest of `f ∘ g ∘ h` or `f . g . h` after the first function, which starts at the
+/// token `start`. With an argument like `(f ∘ g)(x+1)` or `f ∘ g(x+1)` the result is
+/// `f(g(x+1))`, without one it is `f(g(x))` and Parser::composition remembers where it was.
+fn parse_composition(
+    p: &mut Parser,
+    start: usize,
+    first: (String, usize, Option<TreeNode>),
+) -> TreeNode {
+    let mut functions = vec![first];
+    while at_composition(p) {
+        p.index += 1;
+        if !p.tokens.get(p.index).is_some_and(char::is_ascii_alphabetic) {
+            p.error("Expected a function name after ∘".to_string());
+            return TreeNode::Empty;
+        }
+        let name = parse_name(p);
+        if name == "x" {
+            p.index -= 1;
+            p.error("x is no function".to_string());
+            p.index += 1;
+        }
+        let (iterate, count) = parse_iteration(p);
+        functions.push((name, iterate, count));
+    }
+    let arg = if p.tokens.get(p.index) == Some(&'(') {
+        p.index += 1;
+        let arg = parse_additive(p);
+        expect_closing_paren(p);
+        arg
+    } else {
+        p.composition = Some((start, p.index));
+        TreeNode::Var("x".to_string())
+    };
+    functions
+        .into_iter()
+        .rev()
+        .fold(arg, |arg, (name, iterate, count)| {
+            call(name, iterate, count, arg)
+        })
+}
+
+/// Whether `(k=` follows, which makes `sum` and `product` a series and no function call.
+fn starts_series(p: &Parser) -> bool {
+    let mut i = p.index + 1;
+    if p.tokens.get(p.index) != Some(&'(')
+        || !p.tokens.get(i).is_some_and(char::is_ascii_alphabetic)
+    {
+        return false;
+    }
+    while p
+        .tokens
+        .get(i)
+        .is_some_and(|c| c.is_alphanumeric() || *c == '_')
+    {
+        i += 1;
+    }
+    p.tokens.get(i) == Some(&'=')
+}
+
+/// Reads `(k=from..to, term)` after `sum` or `product`.
+fn parse_series(p: &mut Parser, op: char) -> TreeNode {
+    fn integer(p: &mut Parser) -> Option<i64> {
+        let start = p.index;
+        if p.tokens.get(p.index) == Some(&'-') {
+            p.index += 1;
+        }
+        while p.tokens.get(p.index).is_some_and(char::is_ascii_digit) {
+            p.index += 1;
+        }
+        p.tokens[start..p.index]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .ok()
+    }
+    let start = p.index;
+    p.index += 1;
+    let mut index = String::new();
+    while p.tokens[p.index] != '=' {
+        index.push(p.tokens[p.index]);
+        p.index += 1;
+    }
+    if index == "x" {
+        let end = p.index;
+        p.index = start + 1;
+        p.error("The index must not be x".to_string());
+        p.index = end;
+    }
+    p.index += 1;
+    let from = integer(p);
+    let dots = p.tokens.get(p.index..p.index + 2) == Some(&['.', '.']);
+    p.index += 2 * dots as usize;
+    let to = integer(p);
+    let (Some(from), Some(to), true) = (from, to, dots) else {
+        p.error("Expected integer bounds like k=1..9".to_string());
+        return skip_series(p);
+    };
+    let Some(terms) = to.checked_sub(from).and_then(|n| n.checked_add(1)) else {
+        p.index = start + 1;
+        p.error(format!(
+            "{index}={from}..{to} has more than {MAX_SERIES_TERMS} terms"
+        ));
+        return skip_series(p);
+    };
+    let terms = terms.max(0) as usize;
+    if terms > MAX_SERIES_TERMS {
+        p.index = start + 1;
+        p.error(format!(
+            "{index}={from}..{to} has {terms} terms, at most {MAX_SERIES_TERMS}"
+        ));
+        return skip_series(p);
+    }
+    if p.tokens.get(p.index) != Some(&',') {
+        p.error("Expected , after the bounds".to_string());
+        return skip_series(p);
+    }
+    p.index += 1;
+    // The index is no constant of the program.
+    let constants = p.constants.len();
+    let term = parse_additive(p);
+    let mut inner = p.constants.split_off(constants);
+    inner.retain(|(_, name)| name != &index);
+    p.constants.append(&mut inner);
+    expect_closing_paren(p);
+    TreeNode::Series(op, index, from, to, Box::new(term))
+}
+
+/// Skips the rest of a series after an error.
+fn skip_series(p: &mut Parser) -> TreeNode {
+    let mut depth = 1;
+    while p.index < p.tokens.len() && depth > 0 {
+        match p.tokens[p.index] {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+        p.index += 1;
+    }
+    TreeNode::Empty
+}
+
+/// Skips the rest of a piecewise expression after an error.
+fn skip_piecewise(p: &mut Parser) -> TreeNode {
+    while p.index < p.tokens.len() && p.tokens[p.index] != '}' {
+        p.index += 1;
+    }
+    p.index += 1;
+    TreeNode::Empty
+}
+
+fn parse_unary(p: &mut Parser) -> TreeNode {
+    if p.index >= p.tokens.len() {
+        p.error("Expected an expression".to_string());
         return TreeNode::Empty;
     }
 
     // Check for unary operators: +, -
-    match tokens[*index] {
+    match p.tokens[p.index] {
         '+' => {
-            *index += 1;
-            parse_unary(tokens, index) // Unary plus, just skip it
+            p.index += 1;
+            parse_unary(p) // Unary plus, just skip it
         }
         '-' => {
-            *index += 1;
-            // Check if this is a negative number
-            if *index < tokens.len() && (tokens[*index].is_ascii_digit() || tokens[*index] == '.') {
-                // Parse as negative number
+            let offset = p.offsets[p.index];
+            p.index += 1;
+            // Check if this is a negative number that is not the base of a power
+            if starts_number(p, p.index) {
+                let start = p.index;
                 let mut num_str = String::from("-");
-                while *index < tokens.len() && tokens[*index].is_ascii_digit() {
-                    num_str.push(tokens[*index]);
-                    *index += 1;
-                }
-                if *index < tokens.len() && tokens[*index] == '.' {
-                    num_str.push(tokens[*index]);
-                    *index += 1;
-                    while *index < tokens.len() && tokens[*index].is_ascii_digit() {
-                        num_str.push(tokens[*index]);
-                        *index += 1;
-                    }
+                parse_digits(p, &mut num_str);
+                if p.index >= p.tokens.len() || p.tokens[p.index] != '^' {
+                    return TreeNode::Num(num_str);
                 }
-                TreeNode::Num(num_str)
-            } else {
-                // Unary minus operator
-                TreeNode::Op(
-                    '-',
-                    Box::new(TreeNode::Num("0".to_string())),
-                    Box::new(parse_unary(tokens, index)),
-                )
+                p.index = start;
+            }
+            // Unary minus operator
+            let is_square_root = p.tokens.get(p.index) == Some(&'√');
+            let operand = parse_unary(p);
+            if matches!(operand, TreeNode::Op('^', ..)) && !is_square_root {
+                p.warning(
+                    offset,
+                    "-a^b means -(a^b) now, write (-a)^b for the old meaning".to_string(),
+                );
             }
+            TreeNode::Op(
+                '-',
+                Box::new(TreeNode::Num("0".to_string())),
+                Box::new(operand),
+            )
         }
-        _ => parse_atomic(tokens, index),
+        _ => parse_power(p),
     }
 }
 
-// Update parse_power to use parse_unary instead of parse_atomic
-fn parse_power(tokens: &[char], index: &mut usize) -> TreeNode {
-    let mut left = parse_unary(tokens, index);
-    while *index < tokens.len() && tokens[*index] == '^' {
-        let op = tokens[*index];
-        *index += 1;
-        let right = parse_unary(tokens, index);
-        left = TreeNode::Op(op, Box::new(left), Box::new(right));
+/// `√e` is `e^(1/2)`.
+fn square_root_exponent() -> TreeNode {
+    TreeNode::Paren(Box::new(TreeNode::Op(
+        '/',
+        Box::new(TreeNode::Num("1".to_string())),
+        Box::new(TreeNode::Num("2".to_string())),
+    )))
+}
+
+fn parse_power(p: &mut Parser) -> TreeNode {
+    let base = parse_atomic(p);
+    if p.index >= p.tokens.len() || p.tokens[p.index] != '^' {
+        return base;
     }
-    left
+    let offset = p.offsets[p.index];
+    p.index += 1;
+    let exponent = parse_unary(p);
+    if matches!(exponent, TreeNode::Op('^', ..)) {
+        p.warning(
+            offset,
+            "a^b^c means a^(b^c) now, write (a^b)^c for the old meaning".to_string(),
+        );
+    }
+    TreeNode::Op('^', Box::new(base), Box::new(exponent))
 }
 
 pub mod math_trick {
     use super::*;
 
-    pub fn abs(x: Dec) -> String {
+    fn number(n: &str) -> Value {
+        Value::Number(n.parse().unwrap())
+    }
+
+    pub fn abs(x: Dec) -> Value {
         let mut res = x.to_string();
         if res.starts_with('-') {
             res.remove(0);
         }
-        res
+        Value::Number(res.parse().unwrap())
     }
 
-    pub fn ge0(x: Dec) -> String {
+    pub fn ge0(x: Dec) -> Value {
         let nan: Dec = get_nan().parse().unwrap();
         match x {
-            _ if x > nan => "1".to_string(),
-            _ if x < nan => "0".to_string(),
-            _ => "NaN".to_string(),
+            _ if x > nan => number("1"),
+            _ if x < nan => number("0"),
+            _ => Value::Indeterminate,
         }
     }
 
-    pub fn is0(x: Dec) -> String {
+    pub fn is0(x: Dec) -> Value {
         let nan: Dec = get_nan().parse().unwrap();
         match x {
-            _ if x < nan => "0".to_string(),
-            _ if x > nan && x < "1".parse::<Dec>().unwrap() + &nan => "1".to_string(),
-            _ if x > "1".parse::<Dec>().unwrap() + nan => "0".to_string(),
-            _ => "NaN".to_string(),
+            _ if x < nan => number("0"),
+            _ if x > nan && x < "1".parse::<Dec>().unwrap() + &nan => number("1"),
+            _ if x > "1".parse::<Dec>().unwrap() + nan => number("0"),
+            _ => Value::Indeterminate,
         }
     }
 
-    pub fn floor1(x: Dec) -> String {
+    pub fn floor1(x: Dec) -> Value {
         let nan: Dec = get_nan().parse().unwrap();
         match x {
-            _ if x < nan => "0".to_string(),
-            _ if x > nan && x < "1".parse::<Dec>().unwrap() + &nan => "0".to_string(),
+            _ if x < nan => number("0"),
+            _ if x > nan && x < "1".parse::<Dec>().unwrap() + &nan => number("0"),
             _ if x > "1".parse::<Dec>().unwrap() + &nan
                 && x < "2".parse::<Dec>().unwrap() + &nan =>
             {
-                "1".to_string()
+                number("1")
             }
             _ if x > "2".parse::<Dec>().unwrap() + &nan
                 && x < "3".parse::<Dec>().unwrap() + &nan =>
             {
-                "2".to_string()
+                number("2")
             }
             _ if x > "3".parse::<Dec>().unwrap() + &nan
                 && x < "4".parse::<Dec>().unwrap() + &nan =>
             {
-                "3".to_string()
+                number("3")
             }
             _ if x > "4".parse::<Dec>().unwrap() + &nan
                 && x < "5".parse::<Dec>().unwrap() + &nan =>
             {
-                "4".to_string()
+                number("4")
             }
             _ if x > "5".parse::<Dec>().unwrap() + &nan
                 && x < "6".parse::<Dec>().unwrap() + &nan =>
             {
-                "5".to_string()
+                number("5")
             }
             _ if x > "6".parse::<Dec>().unwrap() + &nan
                 && x < "7".parse::<Dec>().unwrap() + &nan =>
             {
-                "6".to_string()
+                number("6")
             }
             _ if x > "7".parse::<Dec>().unwrap() + &nan
                 && x < "8".parse::<Dec>().unwrap() + &nan =>
             {
-                "7".to_string()
+                number("7")
             }
             _ if x > "8".parse::<Dec>().unwrap() + &nan
                 && x < "9".parse::<Dec>().unwrap() + &nan =>
             {
-                "8".to_string()
+                number("8")
             }
             _ if x > "9".parse::<Dec>().unwrap() + &nan
                 && x < "10".parse::<Dec>().unwrap() + &nan =>
             {
-                "9".to_string()
+                number("9")
             }
-            _ if x > "10".parse::<Dec>().unwrap() + &nan => "0".to_string(),
-            _ => "NaN".to_string(),
+            _ if x > "10".parse::<Dec>().unwrap() + &nan => number("0"),
+            _ => Value::Indeterminate,
         }
     }
 
     /// num should be a to_standard_notation_string().
-    pub fn left(x: Dec) -> String {
+    pub fn left(x: Dec) -> Value {
         // left(x) and right(x) only consist of several floor(x*10). That means this here should ne enough to get all NaNs.
         let mut num = dec_to_string(x);
-        if floor1(num.parse::<Dec>().unwrap() * "10".parse::<Dec>().unwrap()) == "NaN" {
-            return "NaN".to_string();
+        if !floor1(num.parse::<Dec>().unwrap() * "10".parse::<Dec>().unwrap()).is_number() {
+            return Value::Indeterminate;
         }
         if !num.contains('.') {
             num += ".0";
@@ -645,7 +2443,7 @@ pub mod math_trick {
         chars.rotate_right(1);
         let rotated_fractional_part: String = chars.into_iter().collect();
         let result = format!("{integer_part}{rotated_fractional_part}");
-        trim_zeros(&result)
+        Value::Number(trim_zeros(&result).parse().unwrap())
     }
 }
 
@@ -653,304 +2451,10 @@ pub mod math_trick {
 mod tests {
     use super::*;
 
+    /// The test cases of the bundled curriculum, loaded once.
     fn get_test_cases() -> &'static Vec<TestCase> {
         static INSTANCE: std::sync::OnceLock<Vec<TestCase>> = std::sync::OnceLock::new();
-        INSTANCE.get_or_init(|| {
-        vec![
-            TestCase {
-                description: None,
-                examples: vec![
-                    ["2".to_string(), get_decimal_places().to_string()],
-                    ["-0.2424".to_string(), get_decimal_places().to_string()],
-                    ["100".to_string(), get_decimal_places().to_string()],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "decimal_places".to_string(),
-                    root_node: parse_expression(&get_decimal_places().to_string())
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    ["-1".to_string(), "1".to_string()],
-                    ["11.2".to_string(), "11.2".to_string()],
-                    ["0".to_string(), "0".to_string()],
-                    ["-0.0025".to_string(), "0.0025".to_string()],
-                    ["1".to_string(), "1".to_string()],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "abs".to_string(),
-                    root_node: parse_expression("(x^2)^0.5")
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    //["0".to_string(), "NaN".to_string()],
-                    ["0.3".to_string(), "1".to_string()],
-                    ["-0.3".to_string(), "0".to_string()],
-                    ["1.0".to_string(), "1".to_string()],
-                    ["400.0".to_string(), "1".to_string()],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "H".to_string(),
-                    root_node: parse_expression("(x+abs(x))/(2*x)")
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    [
-                        "55".to_string(),
-                        "0.".to_string() + &"0".repeat(get_decimal_places() - 1) + "1",
-                    ],
-                    [
-                        "-11.9".to_string(),
-                        "0.".to_string() + &"0".repeat(get_decimal_places() - 1) + "1",
-                    ],
-                    [
-                        "0.0".to_string(),
-                        "0.".to_string() + &"0".repeat(get_decimal_places() - 1) + "1",
-                    ],
-                    [
-                        "-0.95".to_string(),
-                        "0.".to_string() + &"0".repeat(get_decimal_places() - 1) + "1",
-                    ],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "tiny".to_string(),
-                    root_node: parse_expression("10^(-decimal_places(x)))")
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    [
-                        "0.".to_string() + &"9".repeat(get_decimal_places()),
-                        "1".to_string(),
-                    ],
-                    /*[
-                        "-0.".to_string() + &"0".repeat(get_decimal_places()) + "1",
-                        "NaN".to_string(),
-                    ],*/
-                    ["0.3".to_string(), "1".to_string()],
-                    ["-0.3".to_string(), "0".to_string()],
-                    ["1.0".to_string(), "1".to_string()],
-                    ["400.0".to_string(), "1".to_string()],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "ge0".to_string(),
-                    root_node: parse_expression("H(x+tiny(x)/10)")
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    ["0".to_string(), "1".to_string()],
-                    ["-6.4".to_string(), "1".to_string()],
-                    ["1.0".to_string(), "0".to_string()],
-                    ["0.999".to_string(), "1".to_string()],
-                    ["50".to_string(), "0".to_string()],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "lt1".to_string(),
-                    root_node: parse_expression("1-ge0(x-1)")
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    ["0".to_string(), "1".to_string()],
-                    ["0.5".to_string(), "1".to_string()],
-                    ["1".to_string(), "0".to_string()],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "is0".to_string(),
-                    root_node: parse_expression("ge0(x)*lt1(x)")
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    ["1".to_string(), "1".to_string()],
-                    ["1.5".to_string(), "1".to_string()],
-                    ["2".to_string(), "0".to_string()],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "is1".to_string(),
-                    root_node: parse_expression("is0(x-1)")
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    ["2".to_string(), "1".to_string()],
-                    ["2.5".to_string(), "1".to_string()],
-                    ["3".to_string(), "0".to_string()],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "is2".to_string(),
-                    root_node: parse_expression("is0(x-2)")
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    ["3".to_string(), "1".to_string()],
-                    ["3.5".to_string(), "1".to_string()],
-                    ["4".to_string(), "0".to_string()],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "is3".to_string(),
-                    root_node: parse_expression("is0(x-3)")
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    ["4".to_string(), "1".to_string()],
-                    ["4.5".to_string(), "1".to_string()],
-                    ["5".to_string(), "0".to_string()],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "is4".to_string(),
-                    root_node: parse_expression("is0(x-4)")
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    ["5".to_string(), "1".to_string()],
-                    ["5.5".to_string(), "1".to_string()],
-                    ["6".to_string(), "0".to_string()],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "is5".to_string(),
-                    root_node: parse_expression("is0(x-5)")
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    ["6".to_string(), "1".to_string()],
-                    ["6.5".to_string(), "1".to_string()],
-                    ["7".to_string(), "0".to_string()],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "is6".to_string(),
-                    root_node: parse_expression("is0(x-6)"),
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    ["7".to_string(), "1".to_string()],
-                    ["7.5".to_string(), "1".to_string()],
-                    ["8".to_string(), "0".to_string()],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "is7".to_string(),
-                    root_node: parse_expression("is0(x-7)"),
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    ["8".to_string(), "1".to_string()],
-                    ["8.5".to_string(), "1".to_string()],
-                    ["9".to_string(), "0".to_string()],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "is8".to_string(),
-                    root_node: parse_expression("is0(x-8)"),
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    ["9".to_string(), "1".to_string()],
-                    ["9.5".to_string(), "1".to_string()],
-                    ["10".to_string(), "0".to_string()],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "is9".to_string(),
-                    root_node: parse_expression("is0(x-9)"),
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    ["0".to_string(), "0".to_string()],
-                    ["0.2".to_string(), "0".to_string()],
-                    ["1".to_string(), "1".to_string()],
-                    ["1.2".to_string(), "1".to_string()],
-                    ["2".to_string(), "2".to_string()],
-                    ["2.2".to_string(), "2".to_string()],
-                    ["3".to_string(), "3".to_string()],
-                    ["3.2".to_string(), "3".to_string()],
-                    ["4".to_string(), "4".to_string()],
-                    ["4.2".to_string(), "4".to_string()],
-                    ["5".to_string(), "5".to_string()],
-                    ["5.2".to_string(), "5".to_string()],
-                    ["6".to_string(), "6".to_string()],
-                    ["6.2".to_string(), "6".to_string()],
-                    ["7".to_string(), "7".to_string()],
-                    ["7.2".to_string(), "7".to_string()],
-                    ["8".to_string(), "8".to_string()],
-                    ["8.2".to_string(), "8".to_string()],
-                    ["9".to_string(), "9".to_string()],
-                    ["9.2".to_string(), "9".to_string()],
-                ],
-                solution: vec![BinaryAlgebraicExpressionTree {
-                    name: "floor1".to_string(),
-                    root_node: parse_expression(
-                        "is1(x)+2*is2(x)+3*is3(x)+4*is4(x)+5*is5(x)+6*is6(x)+7*is7(x)+8*is8(x)+9*is9(x)",
-                    ),
-                }],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    ["0.06".to_string(), "0.6".to_string()],
-                    [
-                        "0.12345678".to_string(),
-                        "0.2345678".to_string() + &"0".repeat(get_decimal_places() - 8) + "1",
-                    ],
-                    [
-                        "0.7".to_string(),
-                        "0.".to_string() + &"0".repeat(get_decimal_places() - 1) + "7",
-                    ],
-                ],
-                solution: vec![
-                    BinaryAlgebraicExpressionTree {
-                        name: "right".to_string(),
-                        root_node: parse_expression("x*10-floor1(x*10)+floor1(x*10)*tiny(x)")
-                    },
-                ],
-            },
-            TestCase {
-                description: None,
-                examples: vec![
-                    [
-                        "0.2345678".to_string() + &"0".repeat(get_decimal_places() - 8) + "1",
-                        "0.12345678".to_string(),
-                    ],
-                    [
-                        "0.".to_string() + &"0".repeat(get_decimal_places() - 1) + "7",
-                        "0.7".to_string(),
-                    ],
-                ],
-                solution: vec![
-                    BinaryAlgebraicExpressionTree {
-                        name: "left".to_string(),
-                        root_node: parse_expression(
-                            &("right(".repeat(get_decimal_places() - 1) + "(x)" + &")".repeat(get_decimal_places() - 1))
-                        )
-                    },
-                ],
-            },
-        ]
-    })
+        INSTANCE.get_or_init(exercise_pack::curriculum)
     }
 
     #[test]
@@ -961,17 +2465,14 @@ mod tests {
             .flat_map(|task| &task.solution)
             .cloned()
             .collect();
-        for i in 0..tasks.len() {
-            for [input, output] in &tasks[i].examples {
-                let name_function = &tasks[i].solution.last().unwrap().name;
-                let result = trim2(
-                    apply_algebra_to_tree_node(
-                        &tasks[i].solution.last().unwrap().root_node,
-                        &input.parse::<Dec>().unwrap(),
-                        &trees,
-                        true,
-                    )
-                    .unwrap(),
+        for task in tasks {
+            for [input, output] in task.examples.iter().chain(&task.hidden_examples) {
+                let name_function = &task.solution.last().unwrap().name;
+                let result = apply_algebra_to_tree_node(
+                    &task.solution.last().unwrap().root_node,
+                    &input.parse::<Dec>().unwrap(),
+                    &trees,
+                    true,
                 );
                 assert_eq!(
                     format!("{}({}) = {}", name_function, input, output),
@@ -984,15 +2485,9 @@ mod tests {
     #[test]
     fn test_math_tricks() {
         let tasks = get_test_cases();
-        let mut trees: Vec<BinaryAlgebraicExpressionTree> = vec![];
         for task in tasks {
-            for tree in &task.solution {
-                trees.push(tree.clone());
-            }
-        }
-        for i in 0..tasks.len() {
-            for [input, output] in &tasks[i].examples {
-                let name_function = &tasks[i].solution.last().unwrap().name;
+            for [input, output] in task.examples.iter().chain(&task.hidden_examples) {
+                let name_function = &task.solution.last().unwrap().name;
                 let input_dec = input.parse().unwrap();
                 let result = match name_function.as_str() {
                     "abs" => math_trick::abs(input_dec),
@@ -1009,4 +2504,1133 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_undefined_values() {
+        let trees: Vec<BinaryAlgebraicExpressionTree> = get_test_cases()
+            .iter()
+            .flat_map(|task| &task.solution)
+            .cloned()
+            .collect();
+        let eval = |expr: &str, use_math_tricks: bool| {
+            apply_algebra_to_tree_node(&parse_expression(expr), &zero(), &trees, use_math_tricks)
+                .to_string()
+        };
+        assert_eq!(
+            eval("H(0)", false),
+            "Undefined (division by zero at x=0 in H)"
+        );
+        assert_eq!(
+            eval("0*H(x)+1", false),
+            "Undefined (division by zero at x=0 in H)"
+        );
+        assert_eq!(
+            eval("(0-1)^0.5", false),
+            "Undefined (negative number to a non-integer power)"
+        );
+        assert_eq!(eval("0^0", false), "Undefined (0 to a non-positive power)");
+        assert_eq!(eval(&format!("ge0({})", get_nan()), true), "NaN");
+        assert_eq!(eval(&format!("1+is0({})", get_nan()), true), "NaN");
+        assert_eq!(eval("abs(0-2)", true), "2");
+    }
+
+    #[test]
+    fn test_queries() {
+        let program = "f(x) = x*2\ng(x) = 1/x\nf(1)\ng(0)\nf(0..1 step 0.5)";
+        let results = parse_program(program).unwrap().run(false).unwrap();
+        assert_eq!(
+            format_results(&results, OutputFormat::Text),
+            "f(1) = 2\ng(0) = Undefined (division by zero at x=0 in g)\nf(0) = 0\nf(0.5) = 1\nf(1) = 2"
+        );
+        assert_eq!(
+            format_results(&results[..2], OutputFormat::Csv),
+            "function,x,value\nf,1,2\ng,0,Undefined (division by zero at x=0 in g)"
+        );
+        assert_eq!(format_results(&results[..1], OutputFormat::Text), "2");
+        assert!(parse_program("f(1)").unwrap().run(false).is_err());
+        assert!(
+            parse_program("f(x) = x*2\nf(1..0 step 0)")
+                .unwrap()
+                .run(false)
+                .is_err()
+        );
+        let program = "f(x) = x*2\ng(x) = x+1\nf(g(1)/4)\nf(-1..0 step 2^-1)";
+        let results = parse_program(program).unwrap().run(false).unwrap();
+        assert_eq!(
+            format_results(&results, OutputFormat::Text),
+            "f(g(1)/4) = 1\nf(-1) = -2\nf(-0.5) = -1\nf(0) = 0"
+        );
+        // step is a function here, and 1/3 reaches the end.
+        let program = "f(x) = x*3\nstep(x) = 2\nf(0..step(1))\nf(0..1 step 1/3)";
+        let results = parse_program(program).unwrap().run(false).unwrap();
+        let inputs: Vec<&str> = results.iter().map(|r| r.input.as_str()).collect();
+        assert_eq!(inputs.len(), 7);
+        assert_eq!(inputs[..4], ["0", "1", "2", "0"]);
+        assert_eq!(inputs[6], "1");
+        assert_eq!(inputs[4].len(), get_decimal_places() + 2);
+        assert_eq!(results[6].value.to_string(), "3");
+        for query in ["f(x)", "f(abc)", "f(h(1))"] {
+            let program = format!("f(x) = x*2\n{query}");
+            assert!(
+                parse_program(&program)
+                    .map_err(|_| String::new())
+                    .and_then(|p| p.run(false))
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_source_format() {
+        let program = "# Doubles x.\n\nf(x) = x*   # comment\n  2\ng(x) = f(\nx)+1\n\ng(1) # query";
+        let results = parse_program(program).unwrap().run(false).unwrap();
+        assert_eq!(format_results(&results, OutputFormat::Text), "3");
+        let errors = parse_program("f(x) = 1\ng(x) = f(x) +\n  3 $ 4\nf(1").unwrap_err();
+        assert_eq!(
+            errors.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+            vec![
+                "line 3, column 5: Unexpected character $",
+                "line 4, column 1: Invalid function call: f(1",
+            ]
+        );
+        assert_eq!(
+            parse_program("f(x) = (x+1\nf(1)").unwrap_err()[0].to_string(),
+            "line 2, column 5: Missing closing parenthesis"
+        );
+        assert_eq!(
+            try_parse_expression("2+?").unwrap_err()[0].to_string(),
+            "line 1, column 3: Unexpected character ?"
+        );
+        assert!(try_parse_expression("f^[3](x)+abs(x)^2").is_ok());
+    }
+
+    #[test]
+    fn test_check_and_expand() {
+        let program =
+            parse_program("f(x) = x*2\ng(x) = f^[2](x-1)+h(x)\nh(x) = g(x)\nk(1)").unwrap();
+        assert_eq!(
+            program.check(),
+            vec![
+                "Function g calls itself",
+                "Function h calls itself",
+                "Function k not defined"
+            ]
+        );
+        let program = parse_program("f(x) = x*2\ng(x) = f^[2](x-1)/x").unwrap();
+        assert_eq!(
+            create_expression(program.expand("g").unwrap()),
+            "((x-1)*2)*2/x"
+        );
+    }
+
+    #[test]
+    fn test_turing_machine() {
+        let sva = turing_machine::to_sva("1RB1LB_1LA0LC_1RZ1LD_1RD0RA", 50).unwrap();
+        assert!(sva.contains("left(x) = right^[decimals(1)-1](x)\ntm(x) = is0(x)*x+is1(x)*(is0(10*(x-1))*(2+right(x-1+0.1))+is1(10*(x-1))*(2+left(x-1-0.1+0.1)))+"));
+        let program = parse_program(&sva).unwrap();
+        assert!(program.check().is_empty());
+        assert_eq!(
+            program.definitions[program.definitions.len() - 3].root_node,
+            parse_expression("right^[49](x)")
+        );
+        assert!(turing_machine::to_sva("1RB1LB_1LA0L", 50).is_err());
+        assert!(turing_machine::to_sva("1RB1LB_1LA0LE", 50).is_err());
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut repl = repl::Repl::new();
+        assert_eq!(repl.eval("f(x) = x+1"), "");
+        assert_eq!(repl.eval("g(x) = f(x)*2\ng(1)"), "4");
+        assert_eq!(repl.eval("f(x) = x   # redefined"), "");
+        assert_eq!(repl.eval("g(1)"), "2");
+        assert_eq!(repl.eval(":show g"), "g(x) = f(x)*2");
+        assert_eq!(repl.eval(":tree g"), "*\n  f\n    x\n  2");
+        assert_eq!(repl.eval(":show h"), "Function h not defined");
+        assert_eq!(repl.eval(":tricks on"), "Math tricks: on");
+        assert_eq!(repl.eval(":tricks maybe"), "Expected on or off");
+        assert_eq!(repl.eval("h(1)"), "Function h not defined");
+    }
+
+    #[test]
+    fn test_formatter() {
+        let src = "# A program.\n\n\nf(x)=x*2+ # double\n   1\ng(x) = f^[1](x-1)^2-(3*x)\ng(3)   # query\n";
+        let formatted = formatter::format_source(src, formatter::FormatOptions::default()).unwrap();
+        assert_eq!(
+            formatted,
+            "# A program.\n\nf(x) = x*2 + 1  # double\ng(x) = f(x - 1)^2 - (3*x)\ng(3)  # query\n"
+        );
+        let options = formatter::FormatOptions {
+            max_width: Some(12),
+        };
+        let wrapped =
+            formatter::format_source("f(x) = x + x^2 + x^3 + x^4\nf(2)", options).unwrap();
+        assert_eq!(wrapped, "f(x) = x +\n    x^2 +\n    x^3 +\n    x^4\nf(2)\n");
+        assert_eq!(
+            formatter::format_source(&wrapped, options).unwrap(),
+            wrapped
+        );
+        assert!(formatter::format_source("f(x) = (", options).is_err());
+    }
+
+    /// A xorshift generator, so the round trip test needs no extra dependency.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    /// The precedence create_expression assumes for a node, 4 for a unary minus and 6 for atoms.
+    fn precedence(node: &TreeNode) -> u8 {
+        match node {
+            TreeNode::Op('-', zero, operand)
+                if **zero == TreeNode::Num("0".to_string())
+                    && precedence(operand) >= 4
+                    && !matches!(&**operand, TreeNode::Num(n) if !n.starts_with('-')) =>
+            {
+                4
+            }
+            TreeNode::Op('^', ..) => 5,
+            TreeNode::Op('*' | '/', ..) => 3,
+            TreeNode::Op(..) => 2,
+            _ => 6,
+        }
+    }
+
+    /// A random tree. If `shaped`, it has a Paren wherever the grammar needs one, like the trees
+    /// parse_expression returns.
+    fn random_tree(rng: &mut Rng, depth: u32, shaped: bool) -> TreeNode {
+        let paren = |node: TreeNode, needed: bool| {
+            if needed && shaped {
+                TreeNode::Paren(Box::new(node))
+            } else {
+                node
+            }
+        };
+        if depth == 0 || rng.below(4) == 0 {
+            return match rng.below(4) {
+                0 => TreeNode::Var("x".to_string()),
+                1 => TreeNode::Num(format!("-{}", rng.below(10))),
+                2 => TreeNode::Num(format!("{}.{}", rng.below(100), rng.below(10))),
+                _ => TreeNode::Num(rng.below(3).to_string()),
+            };
+        }
+        match rng.below(5) {
+            0 => TreeNode::Paren(Box::new(random_tree(rng, depth - 1, shaped))),
+            1 => TreeNode::Fun(
+                ["f", "g"][rng.below(2) as usize].to_string(),
+                rng.below(3) as usize,
+                Box::new(random_tree(rng, depth - 1, shaped)),
+            ),
+            2 => {
+                let operand = random_tree(rng, depth - 1, shaped);
+                let needed = precedence(&operand) < 4
+                    || matches!(&operand, TreeNode::Num(n) if !n.starts_with('-'));
+                TreeNode::Op(
+                    '-',
+                    Box::new(TreeNode::Num("0".to_string())),
+                    Box::new(paren(operand, needed)),
+                )
+            }
+            _ => {
+                let op = ['+', '-', '*', '/', '^'][rng.below(5) as usize];
+                let prec = precedence(&TreeNode::Op(
+                    op,
+                    Box::new(TreeNode::Empty),
+                    Box::new(TreeNode::Empty),
+                ));
+                let left = random_tree(rng, depth - 1, shaped);
+                let right = random_tree(rng, depth - 1, shaped);
+                let (left_needed, right_needed) = if op == '^' {
+                    let negative = matches!(&left, TreeNode::Num(n) if n.starts_with('-'));
+                    (precedence(&left) < 6 || negative, precedence(&right) < 4)
+                } else {
+                    (precedence(&left) < prec, precedence(&right) <= prec)
+                };
+                TreeNode::Op(
+                    op,
+                    Box::new(paren(left, left_needed)),
+                    Box::new(paren(right, right_needed)),
+                )
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = Rng(0x5eed_1234_abcd_0001);
+        let spaced = ExpressionStyle {
+            spaces: true,
+            ..Default::default()
+        };
+        let unicode = ExpressionStyle {
+            spaces: true,
+            unicode: true,
+            ..Default::default()
+        };
+        for _ in 0..5000 {
+            let tree = random_tree(&mut rng, 5, true);
+            let printed = create_expression(tree.clone());
+            assert_eq!(parse_expression(&printed), tree, "{printed}");
+            let printed = create_expression_styled(tree.clone(), spaced);
+            assert_eq!(
+                try_parse_expression(&printed),
+                Ok(tree.clone()),
+                "{printed}"
+            );
+            let printed = create_expression_styled(tree.clone(), unicode);
+            assert_eq!(try_parse_expression(&printed), Ok(tree), "{printed}");
+
+            let tree = random_tree(&mut rng, 5, false);
+            let reparsed = parse_expression(&create_expression(tree));
+            assert_eq!(
+                parse_expression(&create_expression(reparsed.clone())),
+                reparsed
+            );
+        }
+        for (input, output) in [
+            ("2*-x", "2*-x"),
+            ("0-x*2", "0-x*2"),
+            ("a(x)-(b(x)-x)", "a(x)-(b(x)-x)"),
+            ("f((x))", "f((x))"),
+            ("f^[0](x)", "f^[0](x)"),
+            ("--5", "--5"),
+            ("2^3^2", "2^3^2"),
+            ("(2^3)^2", "(2^3)^2"),
+            ("-2^2", "-2^2"),
+            ("(-2)^2", "(-2)^2"),
+            ("2^-x^2", "2^-x^2"),
+            ("2*x", "2*x"),
+        ] {
+            assert_eq!(create_expression(parse_expression(input)), output);
+        }
+        let tree = TreeNode::Op(
+            '-',
+            Box::new(TreeNode::Var("x".to_string())),
+            Box::new(parse_expression("x-1")),
+        );
+        assert_eq!(create_expression(tree), "x-(x-1)");
+    }
+
+    #[test]
+    fn test_school_math_precedence() {
+        let program =
+            parse_program("f(x) = -2^2\ng(x) = 2^3^2\nh(x) = -x^2+2^-1\nf(0)\ng(0)\nh(3)").unwrap();
+        let values: Vec<String> = program
+            .run(false)
+            .unwrap()
+            .iter()
+            .map(|r| r.value.to_string())
+            .collect();
+        assert_eq!(values, ["-4", "512", "-8.5"]);
+        assert_eq!(
+            parse_expression("-x^2"),
+            TreeNode::Op(
+                '-',
+                Box::new(TreeNode::Num("0".to_string())),
+                Box::new(parse_expression("x^2"))
+            )
+        );
+
+        let warnings =
+            migration_warnings("f(x) = (-x)^2 + 2^(3^2)\ng(x) = 1 - -x^2\nh(x) = 2^3^2\nh(-2^2)");
+        let warnings: Vec<String> = warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            [
+                "line 2, column 12: -a^b means -(a^b) now, write (-a)^b for the old meaning",
+                "line 3, column 9: a^b^c means a^(b^c) now, write (a^b)^c for the old meaning",
+                "line 4, column 3: -a^b means -(a^b) now, write (-a)^b for the old meaning",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_implicit_multiplication() {
+        for (input, output) in [
+            ("2x", "2*x"),
+            ("3(x+1)", "3*(x+1)"),
+            ("(x+1)(x-1)", "(x+1)*(x-1)"),
+            ("2abs(x)", "2*abs(x)"),
+            ("x(x-1)", "x*(x-1)"),
+            ("-2x^2", "-2*x^2"),
+            ("1/2x", "1/(2*x)"),
+            ("2x/3", "2*x/3"),
+        ] {
+            assert_eq!(
+                try_parse_expression(input).map(create_expression),
+                Ok(output.to_string())
+            );
+        }
+        let program = parse_program("f(x) = 1/2x + 3(x+1)(x-1)\nf(2)").unwrap();
+        assert_eq!(program.run(false).unwrap()[0].value.to_string(), "9.25");
+        assert!(parse_program("x(x) = 2\n").is_err());
+
+        let errors: Vec<String> = implicit_multiplications("f(x) = 2*x\ng(x) = 2x + (x)(x)\ng(2x)")
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "line 2, column 9: Implicit multiplication, write * in strict mode",
+                "line 2, column 16: Implicit multiplication, write * in strict mode",
+                "line 3, column 4: Implicit multiplication, write * in strict mode",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unicode_notation() {
+        for (unicode, ascii) in [
+            ("3×x − 2÷x", "3*x-2/x"),
+            ("2·x²", "2*x^2"),
+            ("x⁻¹ + x¹⁰", "x^-1+x^10"),
+            ("√(x+1)", "(x+1)^(1/2)"),
+            ("−√x", "-x^(1/2)"),
+            ("2√x", "2*x^(1/2)"),
+        ] {
+            assert_eq!(
+                try_parse_expression(unicode).map(create_expression),
+                Ok(ascii.to_string())
+            );
+        }
+        let style = ExpressionStyle {
+            spaces: true,
+            unicode: true,
+            ..Default::default()
+        };
+        for (ascii, unicode) in [
+            ("3*x^2-x/2", "3·x² − x÷2"),
+            ("(x+1)^(1/2)-(-2)^x", "√(x + 1) − (−2)^x"),
+            ("-x^-1*x^(2*x)", "−x⁻¹·x^(2·x)"),
+        ] {
+            assert_eq!(
+                create_expression_styled(parse_expression(ascii), style),
+                unicode
+            );
+        }
+        assert_eq!(
+            try_parse_expression("x²³").unwrap(),
+            parse_expression("x^23")
+        );
+        let program = parse_program("f(x) = √(x²) × 2\nf(−3)").unwrap();
+        assert_eq!(program.run(false).unwrap()[0].value.to_string(), "6");
+    }
+
+    #[test]
+    fn test_number_literals() {
+        for (input, output) in [
+            ("1e-50", "1e-50"),
+            ("2.5E+03", "2.5e3"),
+            ("2.50e0", "2.50e0"),
+            (".5", "0.5"),
+            ("-.5x", "-0.5*x"),
+            ("1_000_000", "1000000"),
+            ("1_0.0_1e1_0", "10.01e10"),
+            ("2e2x", "2e2*x"),
+        ] {
+            assert_eq!(create_expression(parse_expression(input)), output);
+        }
+        assert_eq!(create_expression(parse_expression("2e")), "2*e(0)");
+        assert!(try_parse_expression("1__0").is_err());
+        assert!(try_parse_expression("1_").is_err());
+        let program = parse_program(
+            "f(x) = x*1e3 + 1_000\ng(x) = 2^1e1 + .5\nh(x) = x^2e0\nf(1e-3)\ng(0)\nh(-2.5e-1)",
+        )
+        .unwrap();
+        let values: Vec<String> = program
+            .run(false)
+            .unwrap()
+            .iter()
+            .map(|r| r.value.to_string())
+            .collect();
+        assert_eq!(values, ["1001", "1024.5", "0.0625"]);
+        let program = parse_program("f(x) = 2^1e20 + x\nf(1)").unwrap();
+        assert_eq!(
+            program.run(false).unwrap()[0].value,
+            Value::Undefined("exponent too large at x=1 in f".to_string())
+        );
+    }
+
+    #[test]
+    fn test_iteration_count() {
+        let src = "decimals(x) = 3\nh(x) = x+1\nf(x) = h^[decimals(1)-1](x)\nf(1)";
+        let program = parse_program(src).unwrap();
+        assert_eq!(
+            program.definitions[2].root_node,
+            parse_expression("h^[2](x)")
+        );
+        assert_eq!(
+            format_results(&program.run(false).unwrap(), OutputFormat::Text),
+            "3"
+        );
+        assert_eq!(
+            formatter::format_source(src, Default::default()).unwrap(),
+            "decimals(x) = 3\nh(x) = x + 1\nf(x) = h^[decimals(1) - 1](x)\nf(1)\n"
+        );
+
+        let errors =
+            parse_program("d(x) = 2.5\nf(x) = x*2\n  g(x) = f^[d(0)](x)\ng(1)").unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "line 3, column 3: Iteration count d(0) of f is 2.5, expected a non-negative integer"
+        );
+        let errors = parse_program("f(x) = f^[1-x](x)").unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "line 1, column 11: The iteration count must not depend on x"
+        );
+        let program = parse_program("f(x) = g^[c(0)](x)\nf(1)").unwrap();
+        assert_eq!(
+            program.check(),
+            [
+                "Function g not defined, called in f",
+                "Function c not defined, called in f"
+            ]
+        );
+
+        let mut repl = repl::Repl::new();
+        repl.eval("c(x) = 0-1\ng(x) = x*2");
+        repl.eval("f(x) = g^[c(0)+3](x)");
+        assert_eq!(repl.eval("f(1)"), "4");
+        assert_eq!(repl.eval(":show f"), "f(x) = g^[c(0)+3](x)");
+        repl.eval("c(x) = 0-4");
+        assert_eq!(
+            repl.eval("f(1)"),
+            "Undefined (Iteration count c(0)+3 of g is -1, expected a non-negative integer at x=1 in f)"
+        );
+    }
+
+    #[test]
+    fn test_constants() {
+        let src = "decimals = 3\ntiny(x) = 10^(-decimals(x))\nf(x) = x*tiny + decimals\nf(2)";
+        let program = parse_program(src).unwrap();
+        assert_eq!(program.constants(), ["decimals", "tiny"]);
+        assert_eq!(
+            format_results(&program.run(false).unwrap(), OutputFormat::Text),
+            "3.002"
+        );
+        assert_eq!(
+            program.fold_constants(false)[1].root_node,
+            TreeNode::Num("0.001".to_string())
+        );
+        // fmt keeps constants the way they are written.
+        let src =
+            "decimals = 3\ntiny(x) = 10^(-decimals)\nf(x) = x*tiny + decimals(1)\nf(decimals)\n";
+        assert_eq!(
+            formatter::format_source(src, Default::default()).unwrap(),
+            src
+        );
+
+        let errors = parse_program("c = x+1\ng(x) = x\nh(x) = 2*g\ni(x) = 2*k\n").unwrap_err();
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            [
+                "line 1, column 1: Constant c depends on x, write c(x) = ...",
+                "line 3, column 10: g depends on x and needs an argument",
+            ]
+        );
+
+        let program = parse_program("z(x) = 1/0\nf(x) = z + x\nf(1)").unwrap();
+        assert_eq!(
+            format_results(&program.run(false).unwrap(), OutputFormat::Text),
+            "Undefined (division by zero at x=0 in z)"
+        );
+    }
+
+    #[test]
+    fn test_prelude() {
+        // The curriculum teaches the prelude.
+        let solutions: Vec<&BinaryAlgebraicExpressionTree> = get_test_cases()
+            .iter()
+            .filter_map(|task| task.solution.last())
+            .collect();
+        for tree in prelude::definitions(prelude::VERSION).unwrap() {
+            assert!(solutions.contains(&&tree), "{} is not tested", tree.name);
+        }
+
+        let src = "use prelude\ndecimals = 5\nf(x) = floor1(x) + left(0.00003)\nf(7.5)";
+        let program = parse_program(src).unwrap();
+        assert_eq!(program.definitions.len(), 20);
+        assert_eq!(
+            format_results(&program.run(false).unwrap(), OutputFormat::Text),
+            "7.3"
+        );
+        assert_eq!(
+            formatter::format_source("use   prelude 1\n", Default::default()).unwrap(),
+            "use prelude 1\n"
+        );
+        let errors: Vec<String> = parse_program("use prelude 2\n  use lib")
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "line 1, column 1: Unknown prelude version, the latest is 1",
+                "line 2, column 3: Expected use prelude or use prelude N",
+            ]
+        );
+        assert_eq!(
+            parse_program("use prelude\nabs(x) = x\ndecimals = 2\n")
+                .unwrap()
+                .check(),
+            ["Function abs is defined twice"]
+        );
+    }
+
+    #[test]
+    fn test_imports() {
+        let dir = std::env::temp_dir().join(format!("sva_imports_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        let write = |name: &str, src: &str| std::fs::write(dir.join(name), src).unwrap();
+        write(
+            "lib/digits.sva",
+            "use prelude\nfirst(x) = floor1(10*x)\nfirst(0.5)\n",
+        );
+        write(
+            "lib/tape.sva",
+            "import \"digits.sva\"\nsecond(x) = first(right(x))\n",
+        );
+        write(
+            "main.sva",
+            "decimals = 3\nimport \"lib/tape.sva\"\nimport \"lib/digits.sva\"\nsecond(0.12)\n",
+        );
+        let main = dir.join("main.sva");
+        let src = std::fs::read_to_string(&main).unwrap();
+        let program = parse_program(&import::resolve_imports(&src, Some(&main)).unwrap()).unwrap();
+        assert_eq!(program.definitions.len(), 21);
+        assert_eq!(
+            format_results(&program.run(false).unwrap(), OutputFormat::Text),
+            "2"
+        );
+        let flat = import::flatten(&src, Some(&main)).unwrap();
+        assert!(flat.contains("left(x) = right^[2](x)\nfirst(x) = floor1(10*x)\n"));
+        assert_eq!(parse_program(&flat).unwrap().definitions.len(), 21);
+        assert_eq!(
+            formatter::format_source("import   \"a.sva\"\n", Default::default()).unwrap(),
+            "import \"a.sva\"\n"
+        );
+
+        // Queries with a = inside brackets are no definitions, those of imported files are dropped.
+        write("lib/digits.sva", "f(x) = x*2\nf(sum(k=1..3, k))\n");
+        let query = "import \"lib/digits.sva\"\nf(sum(k=1..3, k))\n";
+        let program = parse_program(&import::resolve_imports(query, Some(&main)).unwrap()).unwrap();
+        assert_eq!(
+            format_results(&program.run(false).unwrap(), OutputFormat::Text),
+            "12"
+        );
+        let flat = import::flatten(query, Some(&main)).unwrap();
+        assert_eq!(flat.matches("f(sum(k=1..3, k))").count(), 1);
+
+        write("lib/digits.sva", "second(x) = x\n");
+        assert_eq!(
+            import::resolve_imports(&src, Some(&main)).unwrap_err(),
+            format!(
+                "Function second is defined in {} and {}",
+                dir.join("lib/tape.sva").display(),
+                dir.join("lib/digits.sva").display()
+            )
+        );
+        write("lib/digits.sva", "import \"tape.sva\"\n");
+        assert_eq!(
+            import::resolve_imports(&src, Some(&main)).unwrap_err(),
+            "Import cycle: main.sva -> tape.sva -> digits.sva -> tape.sva"
+        );
+        write("lib/digits.sva", "f(x) = (x\n");
+        assert_eq!(
+            import::resolve_imports(&src, Some(&main)).unwrap_err(),
+            format!(
+                "{}: line 1, column 10: Missing closing parenthesis",
+                dir.join("lib/digits.sva").display()
+            )
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_piecewise() {
+        let tree = parse_expression("{ -1 if x < 0; 0 if x == 0; 1 otherwise }");
+        assert_eq!(
+            create_expression(tree.clone()),
+            "{ -1 if x < 0; 0 if x == 0; 1 otherwise }"
+        );
+        assert_eq!(
+            create_expression(lower_piecewise(&tree)),
+            "lt1(x+1)*-1+(1-lt1(x+1))*(1-is0(x))"
+        );
+        let tree = parse_expression("{a if x≥1;b otherwise}");
+        assert_eq!(
+            create_expression(tree.clone()),
+            "{ a(0) if x >= 1; b(0) otherwise }"
+        );
+        assert_eq!(
+            create_expression(lower_piecewise(&tree)),
+            "ge0(x-1)*a(0)+(1-ge0(x-1))*b(0)"
+        );
+        assert_eq!(
+            create_expression(lower_piecewise(&parse_expression(
+                "{ x if x > 2; 2x if x <= 1; 0 otherwise }"
+            ))),
+            "lt1(2-x+1)*x+(1-lt1(2-x+1))*ge0(1-x)*(2*x)"
+        );
+
+        let src =
+            "use prelude\ndecimals = 5\nf(x) = {\n  x^2 if x < 1;\n  x otherwise\n}\nf(-2..3)";
+        let program = parse_program(src).unwrap();
+        assert!(!contains_node(&program.definitions[19].root_node, &|n| {
+            matches!(n, TreeNode::Piecewise(..))
+        }));
+        assert_eq!(
+            format_results(&program.run(false).unwrap(), OutputFormat::Text),
+            "f(-2) = 4\nf(-1) = 1\nf(0) = 0\nf(1) = 1\nf(2) = 2\nf(3) = 3"
+        );
+        assert_eq!(
+            formatter::format_source("f(x)={x if x<1;2 otherwise}\n", Default::default()).unwrap(),
+            "f(x) = { x if x < 1; 2 otherwise }\n"
+        );
+        for (src, message) in [
+            (
+                "{x if x<1 2 otherwise}",
+                "Expected ; after the condition, the last case is otherwise",
+            ),
+            (
+                "{x if x; 2 otherwise}",
+                "Expected <, <=, >, >= or == in the condition",
+            ),
+            (
+                "{x; 2 otherwise}",
+                "Expected if or otherwise after the value of a case",
+            ),
+            (
+                "{x if x<1; 2 otherwise",
+                "Expected } after the otherwise case",
+            ),
+        ] {
+            let (_, errors) = parse_expression_with_errors(src);
+            assert_eq!(
+                errors.iter().map(|(_, e)| e.as_str()).collect::<Vec<_>>(),
+                [message]
+            );
+        }
+    }
+
+    #[test]
+    fn test_series() {
+        let tree = parse_expression("sum(k = 1..3, k*is0(x-k))");
+        assert_eq!(create_expression(tree.clone()), "sum(k=1..3, k*is0(x-k))");
+        assert_eq!(
+            create_expression(expand_series(&tree)),
+            "1*is0(x-1)+2*is0(x-2)+3*is0(x-3)"
+        );
+        assert_eq!(
+            create_expression(expand_series(&parse_expression(
+                "product(i=1..2, x-i) + sum(k=1..0, k) + product(k=1..0, k)"
+            ))),
+            "(x-1)*(x-2)+0+1"
+        );
+        assert_eq!(
+            create_expression(expand_series(&parse_expression(
+                "sum(i=1..2, sum(j=0..1, i*j))"
+            ))),
+            "1*0+1*1+(2*0+2*1)"
+        );
+
+        let src = "k(x) = x\nf(x) = sum(k=1..4, k*x)\nsum(x) = k(x) + 1\nf(2)\nsum(2)";
+        let program = parse_program(src).unwrap();
+        assert_eq!(
+            create_expression(program.definitions[1].root_node.clone()),
+            "1*x+2*x+3*x+4*x"
+        );
+        assert_eq!(
+            format_results(&program.run(false).unwrap(), OutputFormat::Text),
+            "f(2) = 20\nsum(2) = 3"
+        );
+        let src = "f(x) = x\nf(sum(k=1..3, k))";
+        let results = parse_program(src).unwrap().run(false).unwrap();
+        assert_eq!(results[0].value.to_string(), "6");
+        assert_eq!(
+            formatter::format_source("f(x)=product(i = 1..4,x-i)\n", Default::default()).unwrap(),
+            "f(x) = product(i=1..4, x - i)\n"
+        );
+        for (src, message) in [
+            (
+                "sum(k=1..10001, k)",
+                "k=1..10001 has 10001 terms, at most 10000",
+            ),
+            (
+                "sum(k=-9223372036854775808..9223372036854775807, k)",
+                "k=-9223372036854775808..9223372036854775807 has more than 10000 terms",
+            ),
+            ("sum(x=1..2, x)", "The index must not be x"),
+            ("sum(k=1..n, k)", "Expected integer bounds like k=1..9"),
+            ("sum(k=1..2 k)", "Expected , after the bounds"),
+        ] {
+            let (_, errors) = parse_expression_with_errors(src);
+            assert_eq!(
+                errors.iter().map(|(_, e)| e.as_str()).collect::<Vec<_>>(),
+                [message]
+            );
+        }
+    }
+
+    #[test]
+    fn test_composition() {
+        let composed = |s: &str| {
+            create_expression_styled(
+                parse_expression(s),
+                ExpressionStyle {
+                    composition: true,
+                    ..Default::default()
+                },
+            )
+        };
+        assert_eq!(
+            create_expression(parse_expression("left ∘ right ∘ g")),
+            "left(right(g(x)))"
+        );
+        assert_eq!(
+            create_expression(parse_expression("(f . g^[2])(x+1) + f∘g(3)")),
+            "f(g^[2](x+1))+f(g(3))"
+        );
+        assert_eq!(composed("left(right(g(x)))"), "left.right.g");
+        assert_eq!(composed("2*f(g^[2](x+1)) + f(3)"), "2*(f.g^[2])(x+1)+f(3)");
+        for s in ["left.right.g", "2*(f.g^[2])(x+1)+f(3)", "(f.g)(h(x)*2)"] {
+            assert_eq!(composed(&composed(s)), composed(s));
+        }
+        let unicode = ExpressionStyle {
+            composition: true,
+            unicode: true,
+            spaces: true,
+        };
+        assert_eq!(
+            create_expression_styled(parse_expression("f(g(x))"), unicode),
+            "f ∘ g"
+        );
+
+        let src = "f(x) = x + 1\ng(x) = 2x\nh = f ∘ g\nk(x) = (g . f)(x) - h(x)\nh(1)\nk(1)";
+        let program = parse_program(src).unwrap();
+        let canonical = "f(x) = x + 1\ng(x) = 2*x\nh = f ∘ g\ni = g . f\nh(1)\n";
+        assert_eq!(
+            formatter::format_source(canonical, Default::default()).unwrap(),
+            canonical
+        );
+        assert_eq!(
+            format_results(&program.run(false).unwrap(), OutputFormat::Text),
+            "h(1) = 3\nk(1) = 1"
+        );
+        let errors: Vec<String> = parse_program("f(x) = x\nh = f ∘ x\nc = f\n")
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "line 2, column 9: x is no function",
+                "line 3, column 5: f depends on x and needs an argument",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_derivative() {
+        let src = "sq(x) = x^2
+g(x) = x^3 - 2/x
+f(x) = 3x^2 + 2x + 1
+h(x) = sq(g(x)) + sq^[2](x)
+r(x) = (x^2 + 1)^(1/2)/x
+t(x) = right^[3](x)
+right(x) = 10x - 7
+c = 5
+p(x) = 2^x";
+        let program = parse_program(src).unwrap();
+        let derivative = |name| create_expression(program.derivative(name).unwrap());
+        assert_eq!(derivative("f"), "6*x+2");
+        assert_eq!(derivative("g"), "3*x^2+2/x^2");
+        assert_eq!(derivative("h"), "2*g(x)*(3*x^2+2/x^2)+2*sq(x)*(2*x)");
+        assert_eq!(derivative("t"), "1000");
+        assert_eq!(derivative("c"), "0");
+        assert_eq!(
+            program.derivative("p").unwrap_err(),
+            "The derivative of 2^x needs a definition of ln(x)"
+        );
+        assert_eq!(
+            program.derivative("q").unwrap_err(),
+            "Function q not defined"
+        );
+        for name in ["f", "g", "h", "r", "t"] {
+            for x in ["0.5", "1", "2.25"] {
+                program
+                    .verify_derivative(name, &x.parse().unwrap(), 10)
+                    .unwrap();
+            }
+        }
+        let abs = parse_program("abs(x) = (x^2)^(1/2)").unwrap();
+        assert_eq!(
+            abs.verify_derivative("abs", &zero(), 10).unwrap_err(),
+            "abs'(0) is Undefined (0 to a non-positive power) and the finite difference is 0"
+        );
+        assert_eq!(
+            derivative::simplify(&parse_expression("x^2*3 + 0*x - (0 - 2)*x^1 + 2^3")),
+            parse_expression("3*x^2+2*x+8")
+        );
+    }
+
+    #[test]
+    fn test_normal_form() {
+        let src = "f(x) = (x+1)*(x-1)
+g(x) = x^2 - 1
+h(x) = (x^2-1)/(2x-2) + 0.25
+r(x) = 1/(x^2+1) + x/3
+s(x) = x^(1/2)
+t(x) = f^[2](x)
+u(x) = -x^3 + 2.5x
+v(x) = 1/(x-x)
+w(x) = 2^100000000*x
+y(x) = 1.5^2000 + (2^1000)^1000
+z(x) = 1.5^4*x";
+        let program = parse_program(src).unwrap();
+        let normal_form = |name| program.normal_form(name).unwrap().map(|n| n.to_string());
+        assert_eq!(normal_form("f").unwrap(), "x^2-1");
+        assert_eq!(normal_form("h").unwrap(), "1/2*x+3/4");
+        assert_eq!(normal_form("r").unwrap(), "(1/3*x^3+1/3*x+1)/(x^2+1)");
+        assert_eq!(normal_form("t").unwrap(), "x^4-2*x^2");
+        assert_eq!(normal_form("u").unwrap(), "-x^3+5/2*x");
+        assert_eq!(normal_form("s"), None);
+        assert_eq!(normal_form("v"), None);
+        // Huge powers have no normal form instead of taking forever.
+        assert_eq!(normal_form("w"), None);
+        assert_eq!(normal_form("y"), None);
+        assert_eq!(normal_form("z").unwrap(), "81/16*x");
+        assert_eq!(program.same_normal_form("f", "g"), Ok(Some(true)));
+        assert_eq!(program.same_normal_form("f", "t"), Ok(Some(false)));
+        assert_eq!(program.same_normal_form("f", "s"), Ok(None));
+        assert_eq!(
+            program.normal_form("q").unwrap_err(),
+            "Function q not defined"
+        );
+    }
+
+    #[test]
+    fn test_equivalence() {
+        use equivalence::Equivalence;
+        let src = "f(x) = (x+1)*(x-1)
+g(x) = x^2 - 1
+h(x) = x^2 + 1
+a(x) = (x^2)^(1/2)
+b(x) = x*(2*step(x) - 1)
+step(x) = (x/a(x) + 1)/2
+c(x) = a(x) + 1/(x - 3)^2/10^12
+big(x) = x*2^5000
+big2(x) = 2^5000*x + x - x";
+        let program = parse_program(src).unwrap();
+        let equivalent = |f, g| program.equivalent(f, g, 10, false).unwrap();
+        assert_eq!(
+            equivalent("f", "g"),
+            Equivalence::SameNormalForm(program.normal_form("g").unwrap().unwrap())
+        );
+        assert_eq!(
+            equivalent("f", "h"),
+            Equivalence::Counterexample {
+                x: Dec::from(-20),
+                f: Value::Number(Dec::from(399)),
+                g: Value::Number(Dec::from(401)),
+            }
+        );
+        // b(0) divides by zero.
+        let Equivalence::Counterexample { x, f, g } = equivalent("a", "b") else {
+            panic!()
+        };
+        assert_eq!((x, f.is_number(), g.is_number()), (zero(), true, false));
+        // c is within 10^-10 of a except near x=3.
+        let Equivalence::Counterexample { x, .. } = equivalent("a", "c") else {
+            panic!()
+        };
+        assert_eq!(x, Dec::from(3));
+        let samples = equivalence::sample_inputs().len();
+        assert_eq!(equivalent("a", "a"), Equivalence::SameSamples(samples));
+        // 2^5000 has no normal form, so big and big2 are sampled.
+        assert_eq!(equivalent("big", "big2"), Equivalence::SameSamples(samples));
+        assert_eq!(
+            program.equivalent("f", "q", 10, false).unwrap_err(),
+            "Function q not defined"
+        );
+    }
+
+    #[test]
+    fn test_grader() {
+        let json = serde_json::to_string(get_test_cases()).unwrap();
+        let exercises = grader::load_exercises(&json).unwrap();
+        let solutions: Vec<String> = exercises
+            .iter()
+            .filter_map(|task| task.solution.last())
+            .map(|t| format!("{}(x) = {}", t.name, create_expression(t.root_node.clone())))
+            .collect();
+        let options = Default::default();
+        // Without the math tricks, left would take minutes.
+        let exercises = &exercises[..exercises.len() - 1];
+        let report = grader::grade(exercises, &solutions.join("\n"), &options).unwrap();
+        assert_eq!((report.passed, report.total), (79, 79));
+        let abs = &exercises[1..2];
+        // The math tricks would replace abs by the correct one, the grader does not use them.
+        let report = grader::grade(abs, "decimals(x) = 5\nabs(x) = x", &options).unwrap();
+        assert_eq!(report.passed, 3);
+        let student = "abs(x) = x*(2*sign(x) - 1)\nsign(x) = 1";
+        let report = grader::grade(abs, student, &Default::default()).unwrap();
+        assert_eq!(
+            report.to_string(),
+            "abs: 3/5 passed
+  abs(-1): expected 1, got -1 (off by -2)
+  abs: a hidden example failed
+Score: 3/5 examples, 0/1 exercises"
+        );
+        let options = grader::GradeOptions {
+            tolerance: "0.01".parse().unwrap(),
+        };
+        let report = grader::grade(abs, student, &options).unwrap();
+        assert_eq!(report.passed, 4);
+        let report = grader::grade(abs, "f(x) = x", &options).unwrap();
+        assert_eq!(
+            report.exercises[0].examples[0].actual,
+            "Function abs not defined"
+        );
+        assert!(report.to_json().contains("\"passed\": 0"));
+        assert!(grader::grade(abs, "abs(x) = ", &options).is_err());
+        assert!(grader::load_exercises("[{}]").is_err());
+    }
+
+    #[test]
+    fn test_exercise_pack() {
+        let pack = r#"{"name": "Steps", "exercises": [
+            {"description": null, "examples": [["0", "{decimals}"]], "solution": "d(x) = {decimals}"},
+            {"description": "Add tiny", "examples": [["1", "1+10^-{decimals}"]],
+             "hidden_examples": [["2", "2+10^-{decimals}"], ["1/2", "0.5+10^-{decimals}"]],
+             "solution": "t(x) = 10^-d(x)\nf(x) = x + t(x)", "helpers": ["d"], "policy": {"max_nodes": 5}},
+            {"description": null, "examples": [["1", "2+2*10^-{decimals}"]],
+             "solution": "g(x) = 2*f(x)", "helpers": ["f"]}
+        ]}"#;
+        let exercises = exercise_pack::load(pack, 3).unwrap();
+        assert_eq!(exercises[1].examples, [["1", "1.001"]]);
+        assert_eq!(exercises[1].hidden_examples[1], ["0.5", "0.501"]);
+        let names: Vec<&String> = exercises[2].solution.iter().map(|t| &t.name).collect();
+        assert_eq!(names, ["d", "t", "f", "g"]);
+
+        let grade = |src| grader::grade(&exercises, src, &Default::default()).unwrap();
+        // d and f are provided by the pack.
+        let report = grade("d(x) = 3\ng(x) = f(x) + f(x)");
+        assert_eq!(
+            report.to_string(),
+            "d: 1/1 passed
+f: 0/3 passed
+  f(1): expected 1.001, got Function f not defined
+  f: a hidden example failed
+  f: a hidden example failed
+g: 1/1 passed
+Score: 2/5 examples, 2/3 exercises"
+        );
+        let report = grade("f(x) = x + 10^-d(x)\ng(x) = 2*t(x)");
+        assert_eq!(
+            report.exercises[1].examples[0].actual,
+            "f has 8 nodes, at most 5 are allowed"
+        );
+        assert_eq!(
+            report.exercises[2].examples[0].actual,
+            "Function t not defined"
+        );
+        let report = grade("f(x) = x + 0.001\nh(x) = nothing(x)");
+        assert!(report.exercises[1].passed());
+        assert!(report.to_json().contains("\"hidden\": true"));
+
+        let errors = [
+            (
+                r#"{"name": "", "exercises": [{"examples": [], "solution": "f(x) = g(x)"}]}"#,
+                "Exercise 1: The solution calls g, which is no helper",
+            ),
+            (
+                r#"{"name": "", "exercises": [{"examples": [], "solution": "f(x) = x", "helpers": ["g"]}]}"#,
+                "Exercise 1: The helper g is no function of an earlier exercise",
+            ),
+            (
+                r#"{"name": "", "exercises": [{"examples": [["1/0", "1"]], "solution": "f(x) = x"}]}"#,
+                "Exercise 1: The example 1/0 is Undefined (division by zero)",
+            ),
+        ];
+        for (pack, error) in errors {
+            assert_eq!(exercise_pack::load(pack, 3).unwrap_err(), error);
+        }
+        assert!(exercise_pack::load(r#"{"name": "", "exercises": [], "level": 1}"#, 3).is_err());
+    }
+
+    #[test]
+    fn test_policy() {
+        let program =
+            parse_program("f(x) = 2^x + g(x)*10.0\ng(x) = -h(x)\nh(x) = {1 if x < 0; x otherwise}")
+                .unwrap();
+        let policy = policy::Policy {
+            operators: Some("+-*/".to_string()),
+            literals: Some(vec!["0".to_string(), "1".to_string(), "10".to_string()]),
+            max_depth: Some(4),
+            max_nodes: Some(7),
+            banned: vec!["h".to_string(), "lt1".to_string()],
+            max_helpers: Some(1),
+        };
+        assert_eq!(
+            policy.check(&program.definitions, "f"),
+            [
+                "2 helper definitions, at most 1 are allowed",
+                "f has 8 nodes, at most 7 are allowed",
+                "f uses ^, allowed are +-*/",
+                "f uses the number 2, allowed are 0, 1, 10",
+                "g calls the banned function h",
+                "The banned function h is defined",
+                "h calls the banned function lt1",
+                "h has 13 nodes, at most 7 are allowed",
+                "h has depth 6, at most 4 is allowed",
+            ]
+        );
+        assert!(
+            policy::Policy::default()
+                .check(&program.definitions, "f")
+                .is_empty()
+        );
+        let program = parse_program("c = 5\nf(x) = c*x").unwrap();
+        let policy = policy::Policy {
+            literals: Some(vec!["5".to_string()]),
+            ..Default::default()
+        };
+        assert!(policy.check(&program.definitions, "f").is_empty());
+
+        // ge0 allows depth 5, also in the functions of earlier exercises the program defines.
+        let exercises = &get_test_cases()[4..5];
+        let src = "H(x) = x*x*x*x*x*x\nge0(x) = H(x)";
+        let report = grader::grade(exercises, src, &Default::default()).unwrap();
+        assert_eq!(
+            report.exercises[0].examples[0].actual,
+            "H has depth 6, at most 5 is allowed"
+        );
+        // The helpers the grader adds are not checked.
+        let report = grader::grade(exercises, "ge0(x) = H(x)", &Default::default()).unwrap();
+        assert_eq!(report.exercises[0].examples[1].actual, "1");
+        // A definition named like a helper of the solution is checked like any other.
+        let pack = r#"{"name": "Plus", "exercises": [{"description": null, "examples": [["1", "3"]],
+            "solution": "h(x) = x + x\nf(x) = h(x) + 1", "policy": {"operators": "+"}}]}"#;
+        let exercises = exercise_pack::load(pack, 5).unwrap();
+        let src = "f(x) = h(x) + 1\nh(x) = x^1 + x";
+        let report = grader::grade(&exercises, src, &Default::default()).unwrap();
+        assert_eq!(
+            report.exercises[0].examples[0].actual,
+            "h uses ^, allowed are +"
+        );
+        let report = grader::grade(&exercises, "f(x) = x + x + 1", &Default::default()).unwrap();
+        assert_eq!(report.passed, 1);
+    }
 }
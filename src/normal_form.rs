@@ -0,0 +1,440 @@
+//! The normal form of polynomials and rational functions behind `normalize`.
+use super::*;
+
+/// Normal forms of higher degree are not computed.
+pub const MAX_DEGREE: usize = 1000;
+
+/// Normal forms with a coefficient whose numerator or denominator has more digits are not
+/// computed, so `2^1000*x` has one but `(2^1000)^1000*x` has none.
+pub const MAX_DIGITS: u64 = 1000;
+
+/// An exact fraction of two integers in lowest terms with a positive denominator.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rational {
+    numerator: Dec,
+    denominator: Dec,
+}
+
+impl Rational {
+    fn new(numerator: Dec, denominator: Dec) -> Self {
+        let sign = if denominator < zero() {
+            Dec::from(-1)
+        } else {
+            Dec::from(1)
+        };
+        let (mut a, mut b) = (numerator.abs(), denominator.abs());
+        while b != zero() {
+            (a, b) = (b.clone(), a % b);
+        }
+        if a == zero() {
+            return Rational::integer(0);
+        }
+        Rational {
+            numerator: (numerator * &sign / &a).with_scale(0),
+            denominator: (denominator * sign / a).with_scale(0),
+        }
+    }
+
+    fn integer(n: i64) -> Self {
+        Rational {
+            numerator: Dec::from(n),
+            denominator: Dec::from(1),
+        }
+    }
+
+    /// The exact value of a number like `-2.5` or `1e-3`.
+    fn parse(n: &str) -> Option<Self> {
+        let plain = dec_to_string(n.parse().ok()?);
+        let (integer, fraction) = plain.split_once('.').unwrap_or((&plain, ""));
+        let denominator: Dec = format!("1{}", "0".repeat(fraction.len())).parse().ok()?;
+        Some(Rational::new(
+            format!("{integer}{fraction}").parse().ok()?,
+            denominator,
+        ))
+    }
+
+    fn is_zero(&self) -> bool {
+        self.numerator == zero()
+    }
+
+    fn digits(&self) -> u64 {
+        self.numerator.digits().max(self.denominator.digits())
+    }
+
+    fn add(&self, other: &Rational) -> Rational {
+        Rational::new(
+            &self.numerator * &other.denominator + &other.numerator * &self.denominator,
+            &self.denominator * &other.denominator,
+        )
+    }
+
+    fn neg(&self) -> Rational {
+        Rational::new(-self.numerator.clone(), self.denominator.clone())
+    }
+
+    fn mul(&self, other: &Rational) -> Rational {
+        Rational::new(
+            &self.numerator * &other.numerator,
+            &self.denominator * &other.denominator,
+        )
+    }
+
+    fn div(&self, other: &Rational) -> Rational {
+        Rational::new(
+            &self.numerator * &other.denominator,
+            &self.denominator * &other.numerator,
+        )
+    }
+
+    /// `3`, `-3` or `-3/4`.
+    fn to_tree(&self) -> TreeNode {
+        let numerator = TreeNode::Num(trim2(self.numerator.clone()));
+        if self.denominator == 1 {
+            return numerator;
+        }
+        TreeNode::Op(
+            '/',
+            Box::new(numerator),
+            Box::new(TreeNode::Num(trim2(self.denominator.clone()))),
+        )
+    }
+}
+
+/// The coefficients of x^0, x^1, ... without trailing zeros, so 0 has none.
+type Polynomial = Vec<Rational>;
+
+fn trimmed(mut p: Polynomial) -> Polynomial {
+    while p.last().is_some_and(Rational::is_zero) {
+        p.pop();
+    }
+    p
+}
+
+fn add(p: &Polynomial, q: &Polynomial) -> Polynomial {
+    let zero = Rational::integer(0);
+    trimmed(
+        (0..p.len().max(q.len()))
+            .map(|i| p.get(i).unwrap_or(&zero).add(q.get(i).unwrap_or(&zero)))
+            .collect(),
+    )
+}
+
+fn scale(p: &Polynomial, factor: &Rational) -> Polynomial {
+    trimmed(p.iter().map(|c| c.mul(factor)).collect())
+}
+
+fn mul(p: &Polynomial, q: &Polynomial) -> Polynomial {
+    if p.is_empty() || q.is_empty() {
+        return Vec::new();
+    }
+    let mut product = vec![Rational::integer(0); p.len() + q.len() - 1];
+    for (i, a) in p.iter().enumerate() {
+        for (j, b) in q.iter().enumerate() {
+            product[i + j] = product[i + j].add(&a.mul(b));
+        }
+    }
+    trimmed(product)
+}
+
+/// The quotient and remainder of the division by a non-zero polynomial.
+fn div_rem(p: &Polynomial, q: &Polynomial) -> (Polynomial, Polynomial) {
+    let mut remainder = p.clone();
+    let mut quotient = vec![Rational::integer(0); p.len().saturating_sub(q.len()) + 1];
+    let lead = q.last().unwrap();
+    while remainder.len() >= q.len() {
+        let shift = remainder.len() - q.len();
+        let factor = remainder.last().unwrap().div(lead);
+        let mut subtrahend = vec![Rational::integer(0); shift];
+        subtrahend.extend(scale(q, &factor.neg()));
+        remainder = add(&remainder, &subtrahend);
+        quotient[shift] = factor;
+    }
+    (trimmed(quotient), remainder)
+}
+
+/// The greatest common divisor with leading coefficient 1.
+fn gcd(p: &Polynomial, q: &Polynomial) -> Polynomial {
+    let (mut a, mut b) = (p.clone(), q.clone());
+    while !b.is_empty() {
+        let remainder = div_rem(&a, &b).1;
+        (a, b) = (b, remainder);
+    }
+    match a.last() {
+        Some(lead) => scale(&a, &Rational::integer(1).div(lead)),
+        None => a,
+    }
+}
+
+/// A polynomial or rational function of x in lowest terms with exact coefficients. The
+/// denominator has the leading coefficient 1, so equal functions have equal normal forms.
+/// Common factors are cancelled, so `(x^2-1)/(x-1)` is `x+1`, which differs at x=1. Likewise
+/// `x^0` is 1, although `0^0` is Undefined.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RationalFunction {
+    numerator: Polynomial,
+    denominator: Polynomial,
+}
+
+impl RationalFunction {
+    fn new(numerator: Polynomial, denominator: Polynomial) -> Option<Self> {
+        if denominator.is_empty() {
+            return None;
+        }
+        let common = gcd(&numerator, &denominator);
+        let numerator = div_rem(&numerator, &common).0;
+        let denominator = div_rem(&denominator, &common).0;
+        let lead = Rational::integer(1).div(denominator.last().unwrap());
+        let result = RationalFunction {
+            numerator: scale(&numerator, &lead),
+            denominator: scale(&denominator, &lead),
+        };
+        let coefficients = result.numerator.iter().chain(&result.denominator);
+        let small = coefficients.map(Rational::digits).max() <= Some(MAX_DIGITS);
+        (result.degree() <= MAX_DEGREE && small).then_some(result)
+    }
+
+    fn constant(c: Rational) -> Self {
+        RationalFunction {
+            numerator: trimmed(vec![c]),
+            denominator: vec![Rational::integer(1)],
+        }
+    }
+
+    fn degree(&self) -> usize {
+        self.numerator
+            .len()
+            .max(self.denominator.len())
+            .saturating_sub(1)
+    }
+
+    /// Whether the denominator is 1.
+    pub fn is_polynomial(&self) -> bool {
+        self.denominator.len() == 1
+    }
+
+    fn add(&self, other: &Self) -> Option<Self> {
+        RationalFunction::new(
+            add(
+                &mul(&self.numerator, &other.denominator),
+                &mul(&other.numerator, &self.denominator),
+            ),
+            mul(&self.denominator, &other.denominator),
+        )
+    }
+
+    fn neg(&self) -> Self {
+        RationalFunction {
+            numerator: scale(&self.numerator, &Rational::integer(-1)),
+            denominator: self.denominator.clone(),
+        }
+    }
+
+    fn mul(&self, other: &Self) -> Option<Self> {
+        RationalFunction::new(
+            mul(&self.numerator, &other.numerator),
+            mul(&self.denominator, &other.denominator),
+        )
+    }
+
+    fn reciprocal(&self) -> Option<Self> {
+        RationalFunction::new(self.denominator.clone(), self.numerator.clone())
+    }
+
+    /// None if `|exponent|` or the degree of the result is above MAX_DEGREE, even for a
+    /// constant, or if a coefficient gets more than MAX_DIGITS digits.
+    fn pow(&self, exponent: i64) -> Option<Self> {
+        let n = usize::try_from(exponent.unsigned_abs()).ok()?;
+        if n > MAX_DEGREE || n.checked_mul(self.degree())? > MAX_DEGREE {
+            return None;
+        }
+        // By squaring, every product is checked for too many digits.
+        let mut result = RationalFunction::constant(Rational::integer(1));
+        let mut power = self.clone();
+        let mut n = n;
+        while n > 0 {
+            if n % 2 == 1 {
+                result = result.mul(&power)?;
+            }
+            n /= 2;
+            if n > 0 {
+                power = power.mul(&power)?;
+            }
+        }
+        if exponent < 0 {
+            result.reciprocal()
+        } else {
+            Some(result)
+        }
+    }
+
+    /// `self(inner)`.
+    fn compose(&self, inner: &Self) -> Option<Self> {
+        let horner = |p: &Polynomial| {
+            p.iter().rev().try_fold(
+                RationalFunction::constant(Rational::integer(0)),
+                |result, c| {
+                    result
+                        .mul(inner)?
+                        .add(&RationalFunction::constant(c.clone()))
+                },
+            )
+        };
+        horner(&self.numerator)?.mul(&horner(&self.denominator)?.reciprocal()?)
+    }
+
+    /// The expanded form with descending powers, e.g. `x^2-1/2*x+3` or `(x+1)/(x^2-2)`.
+    pub fn to_tree(&self) -> TreeNode {
+        fn polynomial(p: &Polynomial) -> TreeNode {
+            let mut sum: Option<TreeNode> = None;
+            for (k, c) in p.iter().enumerate().rev().filter(|(_, c)| !c.is_zero()) {
+                let power = match k {
+                    0 => None,
+                    1 => Some(TreeNode::Var("x".to_string())),
+                    k => Some(TreeNode::Op(
+                        '^',
+                        Box::new(TreeNode::Var("x".to_string())),
+                        Box::new(TreeNode::Num(k.to_string())),
+                    )),
+                };
+                let negative = c.numerator < zero();
+                let c = if negative && sum.is_some() {
+                    c.neg()
+                } else {
+                    c.clone()
+                };
+                let term = match power {
+                    None => c.to_tree(),
+                    Some(power) if c == Rational::integer(1) => power,
+                    Some(power) if c == Rational::integer(-1) => TreeNode::Op(
+                        '-',
+                        Box::new(TreeNode::Num("0".to_string())),
+                        Box::new(power),
+                    ),
+                    Some(power) => TreeNode::Op('*', Box::new(c.to_tree()), Box::new(power)),
+                };
+                sum = Some(match sum {
+                    None => term,
+                    Some(sum) => TreeNode::Op(
+                        if negative { '-' } else { '+' },
+                        Box::new(sum),
+                        Box::new(term),
+                    ),
+                });
+            }
+            sum.unwrap_or(TreeNode::Num("0".to_string()))
+        }
+        if self.is_polynomial() {
+            return polynomial(&self.numerator);
+        }
+        TreeNode::Op(
+            '/',
+            Box::new(polynomial(&self.numerator)),
+            Box::new(polynomial(&self.denominator)),
+        )
+    }
+}
+
+impl std::fmt::Display for RationalFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", create_expression(self.to_tree()))
+    }
+}
+
+impl Program {
+    /// The normal form of the definition `name` with all calls inlined, or None if it is no
+    /// polynomial or rational function of x, e.g. because of a power like `x^(1/2)`, a
+    /// division by zero or a degree above MAX_DEGREE.
+    pub fn normal_form(&self, name: &str) -> Result<Option<RationalFunction>, String> {
+        let errors = self.check();
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+        if !self.definitions.iter().any(|t| t.name == name) {
+            return Err(format!("Function {name} not defined"));
+        }
+        let x = RationalFunction::new(
+            vec![Rational::integer(0), Rational::integer(1)],
+            vec![Rational::integer(1)],
+        )
+        .unwrap();
+        let call = TreeNode::Fun(
+            name.to_string(),
+            1,
+            Box::new(TreeNode::Var("x".to_string())),
+        );
+        Ok(self.normalize(&call, &x, &mut Vec::new()))
+    }
+
+    /// Whether the definitions `f` and `g` have the same normal form. None if one of them has
+    /// none, then they may still be equal.
+    pub fn same_normal_form(&self, f: &str, g: &str) -> Result<Option<bool>, String> {
+        Ok(match (self.normal_form(f)?, self.normal_form(g)?) {
+            (Some(f), Some(g)) => Some(f == g),
+            _ => None,
+        })
+    }
+
+    /// The normal form of `node` for x = `x`. `normalized` caches the normal forms of the
+    /// definitions, None for those without one.
+    fn normalize(
+        &self,
+        node: &TreeNode,
+        x: &RationalFunction,
+        normalized: &mut Vec<(String, Option<RationalFunction>)>,
+    ) -> Option<RationalFunction> {
+        match node {
+            TreeNode::Num(n) => Some(RationalFunction::constant(Rational::parse(n)?)),
+            TreeNode::Var(_) => Some(x.clone()),
+            TreeNode::Op(op, left, right) => {
+                let left = self.normalize(left, x, normalized)?;
+                let right = self.normalize(right, x, normalized)?;
+                match op {
+                    '+' => left.add(&right),
+                    '-' => left.add(&right.neg()),
+                    '*' => left.mul(&right),
+                    '/' => left.mul(&right.reciprocal()?),
+                    _ => {
+                        let exponent = right.numerator.first().cloned();
+                        let exponent = exponent.unwrap_or(Rational::integer(0));
+                        if right.degree() > 0 || exponent.denominator != 1 {
+                            return None;
+                        }
+                        left.pow(trim2(exponent.numerator).parse().ok()?)
+                    }
+                }
+            }
+            TreeNode::Fun(name, iterate, arg) => {
+                let mut result = self.normalize(arg, x, normalized)?;
+                if *iterate == 0 {
+                    return Some(result);
+                }
+                let body = match normalized.iter().find(|(n, _)| n == name) {
+                    Some((_, body)) => body.clone()?,
+                    None => {
+                        let identity = RationalFunction::new(
+                            vec![Rational::integer(0), Rational::integer(1)],
+                            vec![Rational::integer(1)],
+                        )?;
+                        let tree = self.definitions.iter().find(|t| &t.name == name)?;
+                        let body = self.normalize(&tree.root_node, &identity, normalized);
+                        normalized.push((name.clone(), body.clone()));
+                        body?
+                    }
+                };
+                for _ in 0..*iterate {
+                    result = body.compose(&result)?;
+                }
+                Some(result)
+            }
+            TreeNode::Iterate(name, count, arg) => {
+                let iterate = iteration_count(name, count, &self.definitions, false).ok()?;
+                let call = TreeNode::Fun(name.clone(), iterate, arg.clone());
+                self.normalize(&call, x, normalized)
+            }
+            TreeNode::Piecewise(..) => self.normalize(&lower_piecewise(node), x, normalized),
+            TreeNode::Series(..) => self.normalize(&expand_series(node), x, normalized),
+            TreeNode::Paren(expr) => self.normalize(expr, x, normalized),
+            TreeNode::Empty => Some(RationalFunction::constant(Rational::integer(0))),
+        }
+    }
+}
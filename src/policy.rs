@@ -0,0 +1,130 @@
+//! Restrictions of exercises like "solve without `^`", checked by the grader.
+use super::*;
+
+/// What the definitions of a solution may use. Every field is optional, by default everything
+/// is allowed. Unary minus counts as `0 - x`, so it needs `-` and the number 0.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// The allowed operators, e.g. `+-*/` for no `^`.
+    pub operators: Option<String>,
+    /// The allowed numbers, compared by value, so `10` also allows `10.0`.
+    pub literals: Option<Vec<String>>,
+    /// The most levels of a definition, `x` has 1 and `x+1` has 2. Parentheses do not count.
+    pub max_depth: Option<usize>,
+    /// The most nodes of a definition, see `count_nodes`.
+    pub max_nodes: Option<usize>,
+    /// Functions that must not be defined or called.
+    pub banned: Vec<String>,
+    /// The most definitions besides the function itself.
+    pub max_helpers: Option<usize>,
+}
+
+impl Policy {
+    /// The violations of the definitions, in order. `function` is the name of the function of the
+    /// exercise, the others are its helpers.
+    pub fn check(
+        &self,
+        definitions: &[BinaryAlgebraicExpressionTree],
+        function: &str,
+    ) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Some(max_helpers) = self.max_helpers {
+            let helpers = definitions.iter().filter(|t| t.name != function).count();
+            if helpers > max_helpers {
+                errors.push(format!(
+                    "{helpers} helper definitions, at most {max_helpers} are allowed"
+                ));
+            }
+        }
+        for tree in definitions {
+            let name = &tree.name;
+            if self.banned.contains(name) {
+                errors.push(format!("The banned function {name} is defined"));
+            }
+            let mut called = Vec::new();
+            function_names(&tree.root_node, &mut called);
+            for banned in called.iter().filter(|n| self.banned.contains(n)) {
+                errors.push(format!("{name} calls the banned function {banned}"));
+            }
+            if let Some(max_nodes) = self.max_nodes {
+                let nodes = count_nodes(&tree.root_node);
+                if nodes > max_nodes {
+                    errors.push(format!(
+                        "{name} has {nodes} nodes, at most {max_nodes} are allowed"
+                    ));
+                }
+            }
+            if let Some(max_depth) = self.max_depth {
+                let depth = depth(&tree.root_node);
+                if depth > max_depth {
+                    errors.push(format!(
+                        "{name} has depth {depth}, at most {max_depth} is allowed"
+                    ));
+                }
+            }
+            let mut operators = Vec::new();
+            let mut literals = Vec::new();
+            symbols(&tree.root_node, &mut operators, &mut literals);
+            if let Some(allowed) = &self.operators {
+                for op in operators.iter().filter(|op| !allowed.contains(**op)) {
+                    errors.push(format!("{name} uses {op}, allowed are {allowed}"));
+                }
+            }
+            if let Some(allowed) = &self.literals {
+                let is_allowed = |n: &String| {
+                    let n = n.parse::<Dec>().ok();
+                    allowed.iter().any(|a| a.parse::<Dec>().ok() == n)
+                };
+                for n in literals.iter().filter(|n| !is_allowed(n)) {
+                    errors.push(format!(
+                        "{name} uses the number {n}, allowed are {}",
+                        allowed.join(", ")
+                    ));
+                }
+            }
+        }
+        errors
+    }
+}
+
+fn depth(node: &TreeNode) -> usize {
+    match node {
+        TreeNode::Op(_, left, right) => 1 + depth(left).max(depth(right)),
+        TreeNode::Fun(_, _, arg) | TreeNode::Iterate(_, _, arg) => 1 + depth(arg),
+        TreeNode::Paren(expr) => depth(expr),
+        TreeNode::Piecewise(..) => depth(&lower_piecewise(node)),
+        TreeNode::Series(..) => depth(&expand_series(node)),
+        TreeNode::Num(_) | TreeNode::Var(_) => 1,
+        TreeNode::Empty => 0,
+    }
+}
+
+/// The distinct operators and numbers of `node`, in order of appearance. A constant written
+/// without an argument like `decimals` is stored as `decimals(0)`, so the argument 0 of a call
+/// is no number of the definition.
+fn symbols(node: &TreeNode, operators: &mut Vec<char>, literals: &mut Vec<String>) {
+    match node {
+        TreeNode::Fun(_, _, arg) if **arg == TreeNode::Num("0".to_string()) => {}
+        TreeNode::Op(op, left, right) => {
+            symbols(left, operators, literals);
+            if !operators.contains(op) {
+                operators.push(*op);
+            }
+            symbols(right, operators, literals);
+        }
+        TreeNode::Num(n) => {
+            if !literals.contains(n) {
+                literals.push(n.clone());
+            }
+        }
+        TreeNode::Fun(_, _, arg) | TreeNode::Paren(arg) => symbols(arg, operators, literals),
+        TreeNode::Iterate(_, count, arg) => {
+            symbols(count, operators, literals);
+            symbols(arg, operators, literals);
+        }
+        TreeNode::Piecewise(..) => symbols(&lower_piecewise(node), operators, literals),
+        TreeNode::Series(..) => symbols(&expand_series(node), operators, literals),
+        TreeNode::Var(_) | TreeNode::Empty => {}
+    }
+}
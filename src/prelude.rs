@@ -0,0 +1,66 @@
+//! The standard library behind `use prelude`: the helper functions every translated Turing
+//! machine needs. They call `decimals`, which the program defines, e.g. `decimals(x) = 50`.
+use super::*;
+
+/// The version `use prelude` loads. `use prelude 1` pins a version.
+pub const VERSION: usize = 1;
+
+const V1: &str = "abs(x) = (x^2)^(1/2)
+H(x) = (x+abs(x))/(2*x)
+tiny(x) = 10^(-decimals(x))
+ge0(x) = H(x+tiny(x)/10)
+lt1(x) = 1-ge0(x-1)
+is0(x) = ge0(x)*lt1(x)
+is1(x) = is0(x-1)
+is2(x) = is0(x-2)
+is3(x) = is0(x-3)
+is4(x) = is0(x-4)
+is5(x) = is0(x-5)
+is6(x) = is0(x-6)
+is7(x) = is0(x-7)
+is8(x) = is0(x-8)
+is9(x) = is0(x-9)
+floor1(x) = is1(x)+2*is2(x)+3*is3(x)+4*is4(x)+5*is5(x)+6*is6(x)+7*is7(x)+8*is8(x)+9*is9(x)
+right(x) = x*10-floor1(x*10)+floor1(x*10)*tiny(x)
+left(x) = right^[decimals(1)-1](x)
+";
+
+/// The source of a prelude version.
+pub fn source(version: usize) -> Option<&'static str> {
+    match version {
+        1 => Some(V1),
+        _ => None,
+    }
+}
+
+/// The definitions of a prelude version.
+pub fn definitions(version: usize) -> Option<Vec<BinaryAlgebraicExpressionTree>> {
+    let program = parse_program(source(version)?).expect("The prelude parses");
+    Some(program.definitions)
+}
+
+/// Parses `use prelude` or `use prelude N`.
+pub(crate) fn parse_use(
+    line: &SourceLine,
+) -> Result<Vec<BinaryAlgebraicExpressionTree>, ParseError> {
+    let indent = line.text.chars().take_while(|c| c.is_whitespace()).count();
+    let mut words = line.text.split_whitespace().skip(1);
+    let version = match (words.next(), words.next(), words.next()) {
+        (Some("prelude"), None, None) => VERSION,
+        (Some("prelude"), Some(version), None) => version.parse().unwrap_or(0),
+        _ => {
+            return Err(line.error(indent, "Expected use prelude or use prelude N".to_string()));
+        }
+    };
+    definitions(version).ok_or(line.error(
+        indent,
+        format!("Unknown prelude version, the latest is {VERSION}"),
+    ))
+}
+
+/// Whether a program contains `use prelude`.
+pub(crate) fn is_used(src: &str) -> bool {
+    split_source(src)
+        .iter()
+        .any(|line| line.text.split_whitespace().take(2).eq(["use", "prelude"]))
+}
@@ -0,0 +1,190 @@
+//! The interactive mode behind `repl`. Definitions accumulate and queries are evaluated at once.
+use super::*;
+use std::io::{BufRead, Write};
+
+/// Every entered line is appended to this file in the current directory.
+const HISTORY_FILE: &str = ".sva_history";
+
+const HELP: &str = "Enter definitions like f(x) = x^2 and queries like f(3) or f(0..1 step 0.5).
+A definition replaces an earlier one with the same name.
+  :show [NAME]       Print a definition or all of them
+  :tree NAME         Print the binary tree of a definition
+  :decimals N        Set the decimal places of the math tricks
+  :tricks on|off     Enable or disable the math tricks
+  :history           Print the history of all sessions
+  :help              Print this message
+  :quit              Leave";
+
+pub(crate) struct Repl {
+    definitions: Vec<BinaryAlgebraicExpressionTree>,
+    use_math_tricks: bool,
+}
+
+impl Repl {
+    pub(crate) fn new() -> Self {
+        Repl {
+            definitions: Vec::new(),
+            use_math_tricks: false,
+        }
+    }
+
+    /// Handles one complete input and returns the text to print.
+    pub(crate) fn eval(&mut self, input: &str) -> String {
+        let input = input.trim();
+        let (command, argument) = input.split_once(' ').unwrap_or((input, ""));
+        let argument = argument.trim();
+        match command {
+            ":help" => HELP.to_string(),
+            ":show" if argument.is_empty() => self
+                .definitions
+                .iter()
+                .map(|t| format!("{}(x) = {}", t.name, create_expression(t.root_node.clone())))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            ":show" => match self.find(argument) {
+                Some(t) => format!("{}(x) = {}", t.name, create_expression(t.root_node.clone())),
+                None => format!("Function {argument} not defined"),
+            },
+            ":tree" => match self.find(argument) {
+                Some(t) => {
+                    let mut lines = Vec::new();
+                    tree_lines(&t.root_node, 0, &mut lines);
+                    lines.join("\n")
+                }
+                None => format!("Function {argument} not defined"),
+            },
+            ":decimals" => match argument.parse::<usize>() {
+                Ok(k) if k <= MAX_DECIMAL_PLACES => {
+                    set_decimal_places(k);
+                    format!("Decimal places: {k}")
+                }
+                _ => format!("Expected a number from 0 to {MAX_DECIMAL_PLACES}"),
+            },
+            ":tricks" => match argument {
+                "on" | "off" => {
+                    self.use_math_tricks = argument == "on";
+                    format!("Math tricks: {argument}")
+                }
+                _ => "Expected on or off".to_string(),
+            },
+            ":history" => std::fs::read_to_string(HISTORY_FILE).unwrap_or_default(),
+            _ if command.starts_with(':') => format!("Unknown command {command}, try :help"),
+            _ => self.eval_program(input),
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<&BinaryAlgebraicExpressionTree> {
+        self.definitions.iter().find(|t| t.name == name)
+    }
+
+    /// Imports are relative to the current directory.
+    fn eval_program(&mut self, input: &str) -> String {
+        let input = match import::resolve_imports(input, None) {
+            Ok(input) => input,
+            Err(e) => return e,
+        };
+        let program = match parse_program(&input) {
+            Ok(program) => program,
+            Err(errors) => {
+                return errors
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+            }
+        };
+        for tree in program.definitions {
+            match self.definitions.iter_mut().find(|t| t.name == tree.name) {
+                Some(old) => *old = tree,
+                None => self.definitions.push(tree),
+            }
+        }
+        if program.queries.is_empty() {
+            return String::new();
+        }
+        let program = Program {
+            definitions: self.definitions.clone(),
+            queries: program.queries,
+        };
+        match program.run(self.use_math_tricks) {
+            Ok(results) => format_results(&results, OutputFormat::Text),
+            Err(e) => e,
+        }
+    }
+}
+
+/// One line per node, children are indented. The labels are the ones of level_order_to_array.
+fn tree_lines(node: &TreeNode, depth: usize, lines: &mut Vec<String>) {
+    let indent = "  ".repeat(depth);
+    match node {
+        TreeNode::Op(op, left, right) => {
+            lines.push(format!("{indent}{op}"));
+            tree_lines(left, depth + 1, lines);
+            tree_lines(right, depth + 1, lines);
+        }
+        TreeNode::Num(n) => lines.push(format!("{indent}{n}")),
+        TreeNode::Var(v) => lines.push(format!("{indent}{v}")),
+        TreeNode::Fun(name, iterate, arg) => {
+            if *iterate == 1 {
+                lines.push(format!("{indent}{name}"));
+            } else {
+                lines.push(format!("{indent}{name}^[{iterate}]"));
+            }
+            tree_lines(arg, depth + 1, lines);
+        }
+        TreeNode::Iterate(name, count, arg) => {
+            lines.push(format!(
+                "{indent}{name}^[{}]",
+                create_expression(*count.clone())
+            ));
+            tree_lines(arg, depth + 1, lines);
+        }
+        TreeNode::Paren(expr) => {
+            lines.push(format!("{indent}()"));
+            tree_lines(expr, depth + 1, lines);
+        }
+        TreeNode::Piecewise(..) => tree_lines(&lower_piecewise(node), depth, lines),
+        TreeNode::Series(..) => tree_lines(&expand_series(node), depth, lines),
+        TreeNode::Empty => {}
+    }
+}
+
+/// Reads from stdin until `:quit` or the end of input.
+pub(crate) fn main() {
+    let mut repl = Repl::new();
+    let mut history = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(HISTORY_FILE)
+        .ok();
+    println!("Single Variable Algebra REPL, :help for help");
+    let mut buffer = String::new();
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        print!("{}", if buffer.is_empty() { "> " } else { "... " });
+        let _ = io::stdout().flush();
+        let Some(Ok(line)) = lines.next() else {
+            break;
+        };
+        if let Some(history) = &mut history {
+            let _ = writeln!(history, "{line}");
+        }
+        if line.trim() == ":quit" {
+            break;
+        }
+        buffer += &line;
+        buffer.push('\n');
+        let code: Vec<&str> = buffer
+            .lines()
+            .map(|l| l.split('#').next().unwrap_or_default())
+            .collect();
+        if continues_on_next_line(&code.join(" ")) {
+            continue;
+        }
+        let result = repl.eval(&buffer);
+        if !result.is_empty() {
+            println!("{result}");
+        }
+        buffer.clear();
+    }
+}
@@ -0,0 +1,73 @@
+//! Translates Turing machines in the standard text format (e.g. `1RB1LB_1LA0LC_1RZ1LD_1RD0RA`)
+//! to SVA programs. The state is the integer part of x and the tape are the decimals.
+
+/// The helper functions every translated Turing machine needs, spelled out so the program runs
+/// without `use prelude`.
+fn helpers(tape_length: usize) -> String {
+    format!(
+        "decimals(x) = {tape_length}\n{}",
+        crate::prelude::source(crate::prelude::VERSION).unwrap()
+    )
+}
+
+/// Returns a program that runs the Turing machine for 10000 steps on an empty tape.
+pub fn to_sva(tm: &str, tape_length: usize) -> Result<String, String> {
+    let tm = tm.trim();
+    let states: Vec<&str> = tm.split('_').collect();
+    let symbols = states[0].len() / 3;
+    if states.iter().any(|s| s.len() != symbols * 3) || symbols == 0 {
+        return Err("Turing machine syntax error.".to_string());
+    }
+    if symbols > 10 {
+        return Err("Turing machine has more than 10 symbols.".to_string());
+    }
+    if states.len() < 2 {
+        return Err("Turing machine needs at least 2 states.".to_string());
+    }
+    if states.len() > 9 {
+        return Err("Turing machine has more than 9 states.".to_string());
+    }
+    if tape_length < 2 {
+        return Err("Tape length must be at least 2.".to_string());
+    }
+    let mut tm_definition = "tm(x) = is0(x)*x".to_string();
+    for (index, block) in states.iter().enumerate() {
+        let state = index + 1;
+        tm_definition += &format!("+is{state}(x)*(");
+        let transitions: Vec<char> = block.chars().collect();
+        for (symbol, transition) in transitions.chunks(3).enumerate() {
+            let write = transition[0]
+                .to_digit(10)
+                .filter(|&w| (w as usize) < symbols)
+                .ok_or(format!("Invalid symbol to write in state {state}."))?;
+            let action = match transition[1] {
+                'L' => "left",
+                'R' => "right",
+                _ => return Err(format!("Invalid direction in state {state}.")),
+            };
+            let new_state = match transition[2] {
+                'Z' => 0,
+                c @ 'A'..='Y' if (c as usize - 'A' as usize) < states.len() => {
+                    c as usize - 'A' as usize + 1
+                }
+                _ => return Err(format!("Invalid or undefined new state in state {state}.")),
+            };
+            if symbol > 0 {
+                tm_definition += "+";
+            }
+            let read = if symbol > 0 {
+                format!("-0.{symbol}")
+            } else {
+                String::new()
+            };
+            tm_definition += &format!(
+                "is{symbol}(10*(x-{state}))*({new_state}+{action}(x-{state}{read}+0.{write}))"
+            );
+        }
+        tm_definition += ")";
+    }
+    Ok(format!(
+        "{}{tm_definition}\nf(x) = tm^[10000](x)\nf(1)",
+        helpers(tape_length)
+    ))
+}